@@ -1,17 +1,44 @@
+use gameboy::Event;
 use header::Header;
+use mbc;
+use mbc::Mbc;
 
 use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::ops::{Index, IndexMut, Range};
 
 #[derive(Debug)]
 pub struct Cart {
     pub mem: Vec<u8>,
     pub headers: Vec<Header>,
+    // external ram, switched in at 0xA000
+    pub ram: Vec<u8>,
+    mbc: Mbc,
+    // what happened since the emulator last looked
+    events: Vec<Event>,
+    // where battery backed ram is kept between runs
+    save: Option<PathBuf>,
+    // ram has changed since it was last saved
+    dirty: bool,
 }
 
 impl Cart {
     pub fn new(mem: Vec<u8>) -> Cart {
+        let cart_type = mem.get(0x147).cloned().unwrap_or(0);
+        let ram_size = mem.get(0x149).cloned().unwrap_or(0);
+
+        let mbc = Mbc::new(cart_type, &mem);
+
         Cart {
+            ram: vec![0; ram_bytes(ram_size) + mbc.builtin_ram()],
+            mbc: mbc,
+            events: Vec::new(),
+            save: None,
+            dirty: false,
             mem: mem,
             headers: vec![
                 Header::new("entry point", 0x100..0x104),
@@ -47,6 +74,100 @@ impl Cart {
     pub fn is_valid(&self) -> bool {
         self.checksum() == self.mem[0x14D]
     }
+
+    pub fn read(&self, address: u16) -> u8 {
+        self.mbc.read_rom(&self.mem, address)
+    }
+
+    // The rom itself is read only, so writes go to the memory bank controller
+    pub fn write(&mut self, address: u16, value: u8) {
+        let rumble = self.mbc.rumble();
+        self.mbc.write_rom(address, value);
+        if self.mbc.rumble() != rumble {
+            self.events.push(Event::Rumble(!rumble));
+        }
+    }
+
+    pub fn take_events(&mut self) -> Vec<Event> {
+        mem::replace(&mut self.events, Vec::new())
+    }
+
+    pub fn read_ram(&self, address: u16) -> u8 {
+        self.mbc.read_ram(&self.ram, address)
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        if self.mbc.write_ram(&mut self.ram, address, value) {
+            self.dirty = true;
+        }
+    }
+
+    /// Carts with a battery keep their ram, and clock, when the game boy is off
+    pub fn has_battery(&self) -> bool {
+        match self.mem.get(0x147).cloned().unwrap_or(0) {
+            0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0xFF => true,
+            _ => false,
+        }
+    }
+
+    /// Keep battery backed ram in `path`, starting from what is already saved there
+    pub fn attach_save(&mut self, path: &Path) -> io::Result<()> {
+        self.save = Some(path.to_path_buf());
+
+        let mut data = Vec::new();
+        match fs::File::open(path) {
+            Ok(mut file) => try!(file.read_to_end(&mut data)),
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error),
+        };
+
+        let length = self.ram.len().min(data.len());
+        self.ram[..length].copy_from_slice(&data[..length]);
+        // the real time clock, if there is one, follows the ram
+        self.mbc.load_rtc(&data[length..]);
+        Ok(())
+    }
+
+    /// Write the ram out to the save file, followed by the clock if there is one
+    pub fn save(&mut self) -> io::Result<()> {
+        if let Some(ref path) = self.save {
+            let mut file = try!(fs::File::create(path));
+            try!(file.write_all(&self.ram));
+            try!(file.write_all(&self.mbc.save_rtc()));
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Save only if the ram changed since last time
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.dirty { self.save() } else { Ok(()) }
+    }
+
+    /// The rom bank switched in at 0x4000
+    pub fn rom_bank(&self) -> usize {
+        self.mbc.rom_bank()
+    }
+
+    pub fn set_clock(&mut self, clock: mbc::Clock) {
+        self.mbc.set_clock(clock);
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        self.mbc.tick(cycles);
+    }
+}
+
+// The ram size byte at 0x149 counts in 8KiB banks, except for the 2KiB of code 1
+fn ram_bytes(ram_size: u8) -> usize {
+    match ram_size {
+        0x01 => 0x800,
+        0x02 => 0x2000,
+        0x03 => 0x8000,
+        0x04 => 0x20000,
+        0x05 => 0x10000,
+        _ => 0,
+    }
 }
 
 impl fmt::Display for Cart {
@@ -121,3 +242,55 @@ impl Index<Range<usize>> for Cart {
         &self.mem[range]
     }
 }
+
+#[test]
+fn battery_ram_and_clock_survive_a_restart() {
+    use std::env;
+
+    let mut rom = vec![0; 0x8000];
+    rom[0x147] = 0x10;
+    rom[0x149] = 0x03;
+    let path = env::temp_dir().join("rustboy_battery_test.sav");
+    let _ = fs::remove_file(&path);
+
+    let mut cart = Cart::new(rom.clone());
+    cart.set_clock(mbc::Clock::Emulated);
+    cart.attach_save(&path).unwrap();
+    cart.write(0x0000, 0x0A);
+    cart.write(0x4000, 0x03);
+    cart.write_ram(0xA123, 0x42);
+    cart.write(0x4000, 0x0A);
+    cart.write_ram(0xA000, 0x05);
+    cart.flush().unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 0x8000 + 48);
+
+    let mut cart = Cart::new(rom);
+    cart.set_clock(mbc::Clock::Emulated);
+    cart.attach_save(&path).unwrap();
+    cart.write(0x0000, 0x0A);
+    cart.write(0x4000, 0x03);
+    assert_eq!(cart.read_ram(0xA123), 0x42);
+    cart.write(0x6000, 0x00);
+    cart.write(0x6000, 0x01);
+    cart.write(0x4000, 0x0A);
+    assert_eq!(cart.read_ram(0xA000), 0x05);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn writes_to_disabled_ram_leave_nothing_to_save() {
+    use std::env;
+
+    let mut rom = vec![0; 0x8000];
+    rom[0x147] = 0x03;
+    rom[0x149] = 0x02;
+    let path = env::temp_dir().join("rustboy_disabled_ram_test.sav");
+    let _ = fs::remove_file(&path);
+
+    let mut cart = Cart::new(rom);
+    cart.attach_save(&path).unwrap();
+    cart.write_ram(0xA000, 0x42);
+    cart.flush().unwrap();
+    assert!(!path.exists());
+}
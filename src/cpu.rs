@@ -1,10 +1,21 @@
 use std::fmt;
 
+use crash::CrashReport;
 use memory;
-use window::Window;
+use memory::Bus;
+use gameboy::Model;
+use instruction;
+use instruction::{Instruction, Operand, Address, Condition, Alu, Shift, Reg8, Reg16};
+use std::collections::VecDeque;
+use std::env;
+use std::io;
 use std::sync::{Arc, RwLock};
 use std::fmt::Write;
 
+// How many instructions a crash report looks back
+const HISTORY: usize = 32;
+
+#[derive(Clone, Copy)]
 #[repr(u8)]
 pub enum Flag {
     ZERO = 1 << 7,
@@ -13,7 +24,57 @@ pub enum Flag {
     CARRY = 1 << 4,
 }
 
-pub struct Cpu {
+// Bits of IE (0xFFFF) and IF (0xFF0F), lowest bit has the highest priority
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum Interrupt {
+    VBLANK = 1 << 0,
+    STAT = 1 << 1,
+    TIMER = 1 << 2,
+    SERIAL = 1 << 3,
+    JOYPAD = 1 << 4,
+}
+
+/// What to do with the eleven opcodes the cpu does not define
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IllegalPolicy {
+    // hang until reset, like the hardware
+    Lock,
+    // show the cpu, wait for enter, then carry on tracing every instruction
+    Break,
+    // stop with a crash report
+    Abort,
+}
+
+impl IllegalPolicy {
+    pub fn from_name(name: &str) -> Option<IllegalPolicy> {
+        match name.to_lowercase().as_str() {
+            "lock" => Some(IllegalPolicy::Lock),
+            "break" => Some(IllegalPolicy::Break),
+            "abort" => Some(IllegalPolicy::Abort),
+            _ => None,
+        }
+    }
+}
+
+/// A snapshot of the programmer visible state
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Registers {
+    pub pc: u16,
+    pub sp: u16,
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub ime: bool,
+}
+
+pub struct Cpu<M: Bus = memory::Memory> {
     pc: u16,
     sp: u16,
     reg_a: u8,
@@ -25,12 +86,27 @@ pub struct Cpu {
     reg_h: u8,
     reg_l: u8,
     ime: bool,
-    memory: Arc<RwLock<memory::Memory>>,
+    // EI takes effect after the instruction following it
+    ime_scheduled: bool,
+    memory: Arc<RwLock<M>>,
     operations: usize,
-    running: bool,
+    // the most recent instructions and their addresses
+    history: VecDeque<(u16, Instruction)>,
+    crash_report: Option<CrashReport>,
+    // HALT sleeps until an enabled interrupt is requested
+    halted: bool,
+    // STOP sleeps until a joypad line goes low
+    stopped: bool,
+    // HALT with IME off and an interrupt already pending fails to increment PC
+    halt_bug: bool,
+    // an illegal opcode hangs the cpu for good
+    locked: bool,
+    illegal_policy: IllegalPolicy,
+    // print every instruction as it executes
+    trace: bool,
 }
 
-impl Cpu {
+impl<M: Bus> Cpu<M> {
     fn print_stack_and_vram(&self, height: usize) {
         println!("mem {{\n  stack:\tvram:");
         for depth in 0..height {
@@ -45,32 +121,41 @@ impl Cpu {
             println!("{}   0x{:0>4X}: {:0>2X} \t  0x{:0>4X}: {:0>2X} \t\t",
                      arrow,
                      byte,
-                     memory[byte],
+                     memory.peek(byte),
                      byte - 0x6000,
-                     memory[byte - 0x6000])
+                     memory.peek(byte - 0x6000))
         }
         println!("}}");
 
     }
 
-    fn crash(&mut self, message: String) -> u16 {
-        println!("{:0>4X}: {}", self.operations, self);
-        self.print_stack_and_vram(0xFF);
-        println!("{}", message);
-        self.running = false;
-        0
+    fn crash(&mut self, address: u16, opcode: u8, reason: String) {
+        let sp = self.sp;
+        let stack = (0..64)
+            .map(|offset| sp.wrapping_add(offset))
+            .take_while(|&address| address >= sp && address < 0xFFFF)
+            .map(|address| self.peek(address))
+            .collect();
+
+        let (rom_bank, io, interrupt_enable) = {
+            let memory = self.memory.read().unwrap();
+            (memory.rom_bank(), (0xFF00..0xFF80).map(|address| memory.peek(address)).collect(), memory.peek(0xFFFF))
+        };
+
+        self.crash_report = Some(CrashReport {
+            reason: reason,
+            address: address,
+            opcode: opcode,
+            registers: self.registers(),
+            history: self.history.iter().cloned().collect(),
+            stack: stack,
+            rom_bank: rom_bank,
+            io: io,
+            interrupt_enable: interrupt_enable,
+        });
     }
 
-    /**
-     * Should I set this on new() or run()?
-     * AF 01B0h
-     * BC 0013h
-     * DE 00D8h
-     * HL 014Dh
-     * SP FFFEh
-     * PC 0100h
-     **/
-    pub fn new(memory: Arc<RwLock<memory::Memory>>) -> Cpu {
+    pub fn new(memory: Arc<RwLock<M>>) -> Cpu<M> {
         Cpu {
             pc: 0,
             sp: 0,
@@ -84,14 +169,80 @@ impl Cpu {
             reg_l: 0,
 
             ime: false,
+            ime_scheduled: false,
 
-            running: false,
+            halted: false,
+            stopped: false,
+            halt_bug: false,
+            locked: false,
+            illegal_policy: IllegalPolicy::Lock,
             operations: 0,
+            history: VecDeque::with_capacity(HISTORY),
+            crash_report: None,
+            trace: env::var("DEBUG").is_ok(),
 
             memory: memory,
         }
     }
 
+    /**
+     * Leave the registers as the boot rom of each model would, and start at the cartridge entry point
+     *
+     *       AF    BC    DE    HL    SP    PC
+     * DMG   01B0  0013  00D8  014D  FFFE  0100
+     * MGB   FFB0  0013  00D8  014D  FFFE  0100
+     * SGB   0100  0014  0000  C060  FFFE  0100
+     * CGB   1180  0000  FF56  000D  FFFE  0100
+     **/
+    pub fn skip_boot(&mut self, model: Model) {
+        let (af, bc, de, hl) = match model {
+            Model::Dmg => (0x01B0, 0x0013, 0x00D8, 0x014D),
+            Model::Mgb => (0xFFB0, 0x0013, 0x00D8, 0x014D),
+            Model::Sgb => (0x0100, 0x0014, 0x0000, 0xC060),
+            Model::Cgb => (0x1180, 0x0000, 0xFF56, 0x000D),
+        };
+
+        self.set_reg16(Reg16::AF, af);
+        self.set_reg16(Reg16::BC, bc);
+        self.set_reg16(Reg16::DE, de);
+        self.set_reg16(Reg16::HL, hl);
+        self.sp = 0xFFFE;
+        self.pc = 0x0100;
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            sp: self.sp,
+            a: self.reg_a,
+            f: self.reg_f,
+            b: self.reg_b,
+            c: self.reg_c,
+            d: self.reg_d,
+            e: self.reg_e,
+            h: self.reg_h,
+            l: self.reg_l,
+            ime: self.ime,
+        }
+    }
+
+    // The low nibble of F is always zero
+    #[allow(dead_code)]
+    pub fn set_registers(&mut self, registers: Registers) {
+        self.pc = registers.pc;
+        self.sp = registers.sp;
+        self.reg_a = registers.a;
+        self.reg_f = registers.f & 0xF0;
+        self.reg_b = registers.b;
+        self.reg_c = registers.c;
+        self.reg_d = registers.d;
+        self.reg_e = registers.e;
+        self.reg_h = registers.h;
+        self.reg_l = registers.l;
+        self.ime = registers.ime;
+        self.ime_scheduled = false;
+    }
+
     /** Interrupt master enable.
      * This flag is not mapped to memory and can't be read by any means.
      * The meaning of the flag is not to enable or disable interrupts.
@@ -110,27 +261,50 @@ impl Cpu {
      *   inc a ; This is executed after returning.
      *   ld   [hl+],a
      **/
-    fn ime(&mut self, enable: bool) -> u16 {
-        let size = 1;
-        self.ime = enable;
+    fn ime(&mut self, enable: bool) {
         if enable {
-            self.print_disassembly(format!("IE"), size);
+            self.ime_scheduled = true;
         } else {
-            self.print_disassembly(format!("DI"), size);
+            self.ime = false;
+            self.ime_scheduled = false;
         }
-        size
     }
 
-
+    // Acknowledge the interrupt in IF, and call its vector with further interrupts disabled.
+    // After EI; HALT the interrupt comes before the HALT bug can repeat a byte, and returns to the HALT
     pub fn service(&mut self, vector: u16) {
-        println!("In where we service vector 0x{:0>4X}", vector);
+        let bit = (vector - 0x0040) / 8;
+        self.ime = false;
+        let flag = self.peek(0xFF0F) & !(1 << bit);
+        self.write(0xFF0F, flag);
+
+        let return_address = if self.halt_bug {
+            self.halt_bug = false;
+            self.pc.wrapping_sub(1)
+        } else {
+            self.pc
+        };
+        self.push_word(return_address);
+        self.pc = vector;
+    }
+
+
+    fn read(&self, address: u16) -> u8 {
+        self.memory.read().unwrap().read(address)
     }
 
+    fn write(&mut self, address: u16, value: u8) {
+        self.memory.write().unwrap().write(address, value);
+    }
+
+    // reads for the debug output, which memory hooks shouldn't see
+    fn peek(&self, address: u16) -> u8 {
+        self.memory.read().unwrap().peek(address)
+    }
 
-    // Not sure if this is little-endian or big-endian
+    // words are stored little-endian
     fn read_word(&self, address: u16) -> u16 {
-        let memory = self.memory.read().unwrap();
-        (memory[address + 1] as u16) << 8 | (memory[address + 0] as u16)
+        (self.read(address.wrapping_add(1)) as u16) << 8 | self.read(address) as u16
     }
 
     // It takes 20 clocks to dispatch an interrupt.
@@ -142,19 +316,17 @@ impl Cpu {
     // 3. The high byte of the PC is set to 0, the low byte is set to the address of the handler
     // ($40,$48,$50,$58,$60). This consumes one last machine cycle.
     // The entire ISR should consume a total of 5 machine cycles. This has yet to be tested, but is what the Z80 datasheet implies.
-    pub fn service_interrupts(&mut self) {
+    pub fn service_interrupts(&mut self) -> u8 {
         // If any IF flag and the corresponding IE flag are both '1' and IME is set to '1' too, the CPU will push the current PC into the stack, will jump to the corresponding interrupt vector and set IME to '0'. If IME is '0', this won't happen.
-        // TODO: what does 'this' refer to?
         // If IME='0' and CPU is halted, when any interrupt is triggered by setting any IF flag to '1' with the corresponding bit in IE set to '1', it takes 4 clocks to exit halt mode, even if the CPU doesn't jump to the interrupt vector.
-        if self.ime {
-            self.sp = self.pc;
-            self.sp += 1;
-
+        let interrupts: u8 = self.interrupt_enable() & self.interrupt_flag();
+        if self.ime && interrupts != 0 {
             // service the lowest bit (highest priority) interrupt
-            let interrupts: u8 = self.interrupt_enable() & self.interrupt_flag();
             // TCAGBD 2.2
             self.service(0x0040 + (8 * (interrupts.trailing_zeros() as u16)));
+            return 20;
         }
+        0
     }
 
     // interrupt flag / interrupt enable
@@ -164,709 +336,615 @@ impl Cpu {
     // Bit 2 – Timer Interrupt Requested
     // Bit 1 – LCD STAT Interrupt Requested
     // Bit 0 – Vertical Blank Interrupt Requested (1=Requested)
+    // the cpu polls these itself, rather than over the bus, so hooks don't see it
     fn interrupt_flag(&self) -> u8 {
-        self.memory.read().unwrap()[0xFFFFu16] & 0b00011111
+        self.peek(0xFF0F) & 0b00011111
     }
 
     fn interrupt_enable(&self) -> u8 {
-        self.memory.read().unwrap()[0xFF0Fu16] & 0b00011111
+        self.peek(0xFFFF) & 0b00011111
     }
 
-    pub fn step(&mut self) {
-        // interrupts are serviced before fetching the next instruction
-        self.service_interrupts();
-
-        let instruction = self.fetch();
-        let advance = self.execute(instruction);
-        self.pc += advance;
-        self.operations += 1;
-
-        match self.operations {
-            0x0001 => {
-                println!("MAIN SCREEN TURN ON");
-            }
-            _ => ()
-        }
-
-        match self.pc {
-            0x0000 => println!("START CLEAR VRAM"),
-            0x000C => println!("END CLEAR VRAM\n  START AUDIO"),
-            0x001D => println!("END AUDIO\n  START LOGO"),
-            0x00E0 => {
-                println!("END LOGO\n  START CHECKSUM");
-                self.halt();
-            }
-            _ => ()
-        }
+    pub fn set_illegal_policy(&mut self, policy: IllegalPolicy) {
+        self.illegal_policy = policy;
     }
 
-    fn fetch(&mut self) -> (u8, u8) {
-        let memory = self.memory.read().unwrap();
-        let opcode = memory[self.pc];
-        if opcode == 0xCB {
-            self.pc += 1;
-
-            return (opcode, memory[self.pc]);
-        }
-        (0, opcode)
+    /// False once the cpu has crashed
+    pub fn running(&self) -> bool {
+        self.crash_report.is_none()
     }
 
-    fn execute(&mut self, instruction: (u8, u8)) -> u16 {
-        match instruction {
-            // z80prefix
-            (0xCB, opcode) => {
-                match opcode {
-                    0x7C => self.bit_h(7),
-                    0x11 => self.rl_c(),
-                    _ => self.crash(format!("unrecognized z80 opcode {:0>2X}", opcode)),
-                }
-            }
-            (_, opcode) => {
-                match opcode {
-                    0x00 => self.nop(),
-
-                    0x0C | 0x04 => self.inc(opcode),
-                    //_ => self.dec(opcode),
-
-                    0x13 => self.inc_de(),
-                    0x23 => self.inc_hl(),
-
-                    0x0D => self.dec("c"),
-                    0x1D => self.dec("e"),
-                    0x2D => self.dec("l"),
-                    0x3D => self.dec("a"),
-                    0x05 => self.dec("b"),
-                    0x15 => self.dec("d"),
-                    0x25 => self.dec("h"),
-
-                    0xAF => self.xor_a(),
-
-                    0x17 => self.rla(),
-
-                    // flow
-                    0x18 => self.jr_r8(),
-                    0x28 => self.jr(Flag::ZERO, true),
-                    0x20 => self.jr(Flag::ZERO, false),
-                    0xC3 => self.jmp_a16(),
-
-                    0xCD => self.call(),
-
-                    0xC9 => self.ret(),
-
-                    0x76 => self.halt(),
-                    0xF3 => self.ime(true),
-                    0xFB => self.ime(false),
-
-                    // stack
-                    0xFE => self.cp_d8(),
-
-                    0xC5 => self.push_bc(),
-
-                    0xC1 => self.pop_bc(),
-                    0xE1 => self.pop_hl(),
-
-                    // loading
-                    // TODO: replace with macros
-                    // ld_a
-                    0x7F => self.ld("a", "a"),
-                    0x78 => self.ld("a", "b"),
-                    0x79 => self.ld("a", "c"),
-                    0x7A => self.ld("a", "d"),
-                    0x7B => self.ld("a", "e"),
-                    0x7C => self.ld("a", "h"),
-                    0x7D => self.ld("a", "l"),
-                    // ld_b
-                    0x47 => self.ld("b", "a"),
-                    0x40 => self.ld("b", "b"),
-                    0x41 => self.ld("b", "c"),
-                    0x42 => self.ld("b", "d"),
-                    0x43 => self.ld("b", "e"),
-                    0x44 => self.ld("b", "h"),
-                    0x45 => self.ld("b", "l"),
-                    // ld_c
-                    0x4F => self.ld("c", "a"),
-                    0x48 => self.ld("c", "b"),
-                    0x49 => self.ld("c", "c"),
-                    0x4A => self.ld("c", "d"),
-                    0x4B => self.ld("c", "e"),
-                    0x4c => self.ld("c", "h"),
-                    0x4D => self.ld("c", "l"),
-                    // ld_d
-                    0x57 => self.ld("d", "a"),
-                    0x50 => self.ld("d", "b"),
-                    0x51 => self.ld("d", "c"),
-                    0x52 => self.ld("d", "d"),
-                    0x53 => self.ld("d", "e"),
-                    0x54 => self.ld("d", "h"),
-                    0x55 => self.ld("d", "l"),
-                    // ld_e
-                    0x5F => self.ld("e", "a"),
-                    0x58 => self.ld("e", "b"),
-                    0x59 => self.ld("e", "c"),
-                    0x5A => self.ld("e", "d"),
-                    0x5B => self.ld("e", "e"),
-                    0x5C => self.ld("e", "h"),
-                    0x5D => self.ld("e", "l"),
-                    // ld_h
-                    0x67 => self.ld("h", "a"),
-                    0x60 => self.ld("h", "b"),
-                    0x61 => self.ld("h", "c"),
-                    0x62 => self.ld("h", "d"),
-                    0x63 => self.ld("h", "e"),
-                    0x64 => self.ld("h", "h"),
-                    0x65 => self.ld("h", "l"),
-                    // ld_l
-                    0x6F => self.ld("l", "a"),
-                    0x68 => self.ld("l", "b"),
-                    0x69 => self.ld("l", "c"),
-                    0x6A => self.ld("l", "d"),
-                    0x6B => self.ld("l", "e"),
-                    0x6C => self.ld("l", "h"),
-                    0x6D => self.ld("l", "l"),
-
-                    0x1A => self.ld_a_de(),
-
-                    0x3E => self.ld_a_d8(),
-                    0x06 => self.ld_b_d8(),
-                    0x0E => self.ld_c_d8(),
-                    0x16 => self.ld_d_d8(),
-                    0x1E => self.ld_e_d8(),
-                    0x26 => self.ld_h_d8(),
-                    0x2E => self.ld_l_d8(),
-
-                    0x77 => self.ld_hl_a(),
-
-                    0x11 => self.ld_de_d16(),
-                    0x21 => self.ld_hl_d16(),
-                    0x31 => self.ld_sp_d16(),
-
-                    0x22 => self.ldi_hl_a(),
-
-                    0x32 => self.ldd_hl_a(),
-
-                    0xE0 => self.ldh_a8_a(),
-                    0xF0 => self.ldh_a_a8(),
-
-                    0xE2 => self.ldr_c_a(),
-                    0xEA => self.ld_a16_a(),
-                    _ => {
-                        println!("unrecognized opcode {:0>2X}", opcode);
-                        0
-                    }
-                }
-            }
-        }
+    pub fn crash_report(&self) -> Option<&CrashReport> {
+        self.crash_report.as_ref()
     }
 
-    pub fn get(&self, flag: Flag) -> bool {
-        self.reg_f & flag as u8 != 0
+    /// Run one instruction, returning how many clocks it took.
+    /// Memory sees the same clocks, for anything on the bus that keeps time
+    pub fn step(&mut self) -> u8 {
+        let cycles = self.run();
+        self.memory.write().unwrap().tick(cycles);
+        cycles
     }
 
-    pub fn set(&mut self, flag: Flag, set: bool) {
-        if set {
-            self.reg_f |= flag as u8;
-        } else {
-            self.reg_f &= !(flag as u8);
+    fn run(&mut self) -> u8 {
+        let hooked = {
+            let memory = self.memory.read().unwrap();
+            if self.locked || memory.stalled() {
+                return 4;
+            }
+            memory.hooked()
+        };
+        // so hooks know who is responsible for an interrupt's pushes, too
+        if hooked {
+            self.memory.write().unwrap().set_pc(self.pc);
         }
-    }
 
-    fn rla(&mut self) -> u16 {
-        let size = 1;
-        let amount = { self.memory.read().unwrap()[self.pc + 1] };
-        self.print_disassembly(format!("RLA ({})", amount), size);
-        if self.reg_a & 0b10000000 != 0 {
-            self.set(Flag::CARRY, true)
+        if self.stopped {
+            if self.joypad_pressed() {
+                self.stopped = false;
+            }
+            return 4;
         }
-        self.reg_a = self.reg_a.rotate_left(amount as u32);
-        size
-    }
 
-    fn rl_c(&mut self) -> u16 {
-        let size = 1;
-        let amount = { self.memory.read().unwrap()[self.pc + 1] };
-        self.print_disassembly(format!("RL C ({})", amount), size);
-        self.reg_c.rotate_left(amount as u32);
-        size
-    }
-
-    fn push_bc(&mut self) -> u16 {
-        let size = 1;
-        self.print_disassembly(format!("PUSH BC ${:0>2X}{:0>2X}", self.reg_b, self.reg_c),
-                               size);
-        self.sp -= 1;
-        let mut memory = self.memory.write().unwrap();
-        memory[self.sp] = self.reg_b;
-        self.sp -= 1;
-        memory[self.sp] = self.reg_c;
-        size
-    }
+        let mut wake_cycles = 0;
+        if self.halted {
+            if self.interrupt_enable() & self.interrupt_flag() == 0 {
+                return 4;
+            }
+            // waking up takes 4 clocks, whether or not the interrupt is then serviced
+            self.halted = false;
+            wake_cycles = 4;
+        }
 
-    fn ld_a_d8(&mut self) -> u16 {
-        let size = 2;
-        let memory = self.memory.read().unwrap();
-        let value = memory[self.pc + 1];
-        self.print_disassembly(format!("LD A, 0x{:0>2X}", value), size);
-        self.reg_a = value;
-        size
-    }
+        // interrupts are serviced before fetching the next instruction
+        let interrupt_cycles = self.service_interrupts();
+        if interrupt_cycles > 0 {
+            return wake_cycles + interrupt_cycles;
+        }
 
-    fn ld_b_d8(&mut self) -> u16 {
-        let size = 2;
-        let memory = self.memory.read().unwrap();
-        let value = memory[self.pc + 1];
-        self.print_disassembly(format!("LD B, 0x{:0>2X}", value), size);
-        self.reg_b = value;
-        size
-    }
+        let enable_interrupts = self.ime_scheduled;
 
-    fn ld(&mut self, to: &'static str, from: &'static str) -> u16 {
-        let size = 1;
-        let value = self.reg(from);
+        let address = self.pc;
+        if hooked {
+            self.memory.write().unwrap().execute(address);
+        }
+        let instruction = instruction::decode(|| self.fetch());
+        if self.trace {
+            self.print_disassembly(address, instruction);
+        }
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((address, instruction));
+        let cycles = self.execute(instruction);
+        self.operations += 1;
 
-        match to {
-            "a" => self.reg_a = value,
-            "b" => self.reg_b = value,
-            "c" => self.reg_c = value,
-            "d" => self.reg_d = value,
-            "e" => self.reg_e = value,
-            "h" => self.reg_h = value,
-            "l" => self.reg_l = value,
-            _ => panic!("'{}' does not match a register", to)
-        };
+        // a DI right after EI cancels it
+        if enable_interrupts && self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false;
+        }
 
-        self.print_disassembly(format!("LD {}, {}; {:0>2X}", to, from, value), size);
+        if self.trace {
+            match self.operations {
+                0x0001 => {
+                    println!("MAIN SCREEN TURN ON");
+                }
+                _ => ()
+            }
 
-        size
-    }
+            match self.pc {
+                0x0000 => println!("START CLEAR VRAM"),
+                0x000C => println!("END CLEAR VRAM\n  START AUDIO"),
+                0x001D => println!("END AUDIO\n  START LOGO"),
+                0x00E0 => println!("END LOGO\n  START CHECKSUM"),
+                _ => ()
+            }
+        }
 
-    fn ld_c_d8(&mut self) -> u16 {
-        let size = 2;
-        let memory = self.memory.read().unwrap();
-        let value = memory[self.pc + 1];
-        self.print_disassembly(format!("LD C, 0x{:0>2X}", value), size);
-        self.reg_c = value;
-        size
+        wake_cycles + cycles
     }
 
-    fn ld_d_d8(&mut self) -> u16 {
-        let size = 2;
-        let memory = self.memory.read().unwrap();
-        let value = memory[self.pc + 1];
-        self.print_disassembly(format!("LD D, 0x{:0>2X}", value), size);
-        self.reg_d = value;
-        size
+    // Reading the byte after HALT twice is the same as PC failing to increment once
+    fn fetch(&mut self) -> u8 {
+        let byte = self.read(self.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.pc = self.pc.wrapping_add(1);
+        }
+        byte
     }
 
-    fn ld_e_d8(&mut self) -> u16 {
-        let size = 2;
-        let memory = self.memory.read().unwrap();
-        let value = memory[self.pc + 1];
-        self.print_disassembly(format!("LD E, 0x{:0>2X}", value), size);
-        self.reg_e = value;
-        size
-    }
+    /// Execute a decoded instruction, PC already points past it. Returns the clocks taken
+    fn execute(&mut self, instruction: Instruction) -> u8 {
+        let mut taken = false;
 
-    fn ld_h_d8(&mut self) -> u16 {
-        let size = 2;
-        let memory = self.memory.read().unwrap();
-        let value = memory[self.pc + 1];
-        self.print_disassembly(format!("LD H, ? ; 0x{:0>2X}", value), size);
-        self.reg_h = value;
-        size
-    }
+        match instruction {
+            Instruction::Nop => (),
+            Instruction::Stop => self.stopped = true,
+            Instruction::Halt => self.halt(),
+            Instruction::Di => self.ime(false),
+            Instruction::Ei => self.ime(true),
+
+            // loading
+            Instruction::Ld(to, from) => {
+                let value = self.operand(from);
+                self.set_operand(to, value);
+            }
+            Instruction::LoadA(address) => {
+                let address = self.address(address);
+                self.reg_a = self.read(address);
+            }
+            Instruction::StoreA(address) => {
+                let address = self.address(address);
+                let value = self.reg_a;
+                self.write(address, value);
+            }
+            Instruction::Ld16(to, value) => self.set_reg16(to, value),
+            Instruction::StoreSp(address) => {
+                let sp = self.sp;
+                self.write(address, sp as u8);
+                self.write(address.wrapping_add(1), (sp >> 8) as u8);
+            }
+            Instruction::LdSpHl => self.sp = self.hl(),
+            Instruction::LdHlSp(offset) => {
+                let value = self.sp_plus(offset);
+                self.store_hl(value);
+            }
 
-    fn ld_l_d8(&mut self) -> u16 {
-        let size = 2;
-        let memory = self.memory.read().unwrap();
-        let value = memory[self.pc + 1];
-        self.print_disassembly(format!("LD L, 0x{:0>2X}", value), size);
-        self.reg_l = value;
-        size
-    }
+            // arithmetic
+            Instruction::Inc(to) => self.inc(to),
+            Instruction::Dec(to) => self.dec(to),
+            Instruction::Inc16(to) => {
+                let value = self.reg16(to).wrapping_add(1);
+                self.set_reg16(to, value);
+            }
+            Instruction::Dec16(to) => {
+                let value = self.reg16(to).wrapping_sub(1);
+                self.set_reg16(to, value);
+            }
+            Instruction::AddHl(from) => self.add_hl(from),
+            Instruction::AddSp(offset) => self.sp = self.sp_plus(offset),
+            Instruction::Alu(operation, from) => {
+                let value = self.operand(from);
+                self.alu(operation, value);
+            }
 
-    fn call(&mut self) -> u16 {
-        let size = 3;
-        let address = self.read_word(self.pc + 1);
-        let return_address = self.pc + size;
+            // rotates and accumulator flag operations, the A rotates always clear ZERO
+            Instruction::Rlca => self.rotate_a(Shift::Rlc),
+            Instruction::Rrca => self.rotate_a(Shift::Rrc),
+            Instruction::Rla => self.rotate_a(Shift::Rl),
+            Instruction::Rra => self.rotate_a(Shift::Rr),
+            Instruction::Daa => self.daa(),
+            Instruction::Cpl => {
+                self.reg_a = !self.reg_a;
+                self.set(Flag::SUBTRACT, true);
+                self.set(Flag::HALFCARRY, true);
+            }
+            Instruction::Scf => {
+                self.set(Flag::SUBTRACT, false);
+                self.set(Flag::HALFCARRY, false);
+                self.set(Flag::CARRY, true);
+            }
+            Instruction::Ccf => {
+                let carry = self.get(Flag::CARRY);
+                self.set(Flag::SUBTRACT, false);
+                self.set(Flag::HALFCARRY, false);
+                self.set(Flag::CARRY, !carry);
+            }
 
-        let return_address_high = (return_address >> 8) as u8 & 0xFF;
-        let return_address_low = (return_address >> 0) as u8 & 0xFF;
+            // flow
+            Instruction::Jr(condition, offset) => {
+                taken = self.check(condition);
+                if taken {
+                    self.pc = self.pc.wrapping_add(offset as u16);
+                }
+            }
+            Instruction::Jp(condition, address) => {
+                taken = self.check(condition);
+                if taken {
+                    self.pc = address;
+                }
+            }
+            Instruction::JpHl => self.pc = self.hl(),
+            Instruction::Call(condition, address) => {
+                taken = self.check(condition);
+                if taken {
+                    let return_address = self.pc;
+                    self.push_word(return_address);
+                    self.pc = address;
+                }
+            }
+            Instruction::Ret(condition) => {
+                taken = self.check(condition);
+                if taken {
+                    self.pc = self.pop_word();
+                }
+            }
+            Instruction::Reti => {
+                self.pc = self.pop_word();
+                self.ime = true;
+            }
+            Instruction::Rst(vector) => {
+                let return_address = self.pc;
+                self.push_word(return_address);
+                self.pc = vector;
+            }
 
-        self.print_disassembly(format!("CALL ${:0>4X} (from {:0>2X}{:0>2X})",
-                                       address,
-                                       return_address_high,
-                                       return_address_low),
-                               size);
+            // stack
+            Instruction::Push(from) => {
+                let value = self.reg16(from);
+                self.push_word(value);
+            }
+            Instruction::Pop(to) => {
+                let value = self.pop_word();
+                self.set_reg16(to, value);
+            }
 
-        let mut memory = self.memory.write().unwrap();
-        memory[self.sp] = return_address_low;
-        self.sp = self.sp.wrapping_sub(1);
-        memory[self.sp] = return_address_high;
-        self.sp = self.sp.wrapping_sub(1);
+            // z80prefix
+            Instruction::Shift(operation, to) => {
+                let value = self.operand(to);
+                let result = self.shift(operation, value);
+                self.set_operand(to, result);
+            }
+            Instruction::Bit(bit, from) => {
+                let zero = self.operand(from) & (1 << bit) == 0;
+                self.set(Flag::ZERO, zero);
+                self.set(Flag::SUBTRACT, false);
+                self.set(Flag::HALFCARRY, true);
+            }
+            Instruction::Res(bit, to) => {
+                let value = self.operand(to) & !(1 << bit);
+                self.set_operand(to, value);
+            }
+            Instruction::Set(bit, to) => {
+                let value = self.operand(to) | 1 << bit;
+                self.set_operand(to, value);
+            }
 
-        self.pc = address;
-        0
-    }
+            Instruction::Illegal(opcode) => self.illegal(opcode),
+        }
 
-    fn ld_a_de(&mut self) -> u16 {
-        let size = 1;
-        let address = self.de();
-        self.print_disassembly(format!("LD A, DE ; DE=${:0>4X}", address), size);
-        let memory = self.memory.read().unwrap();
-        self.reg_a = memory[address];
-        size
+        instruction.cycles(taken)
     }
 
-    fn ld_de_d16(&mut self) -> u16 {
-        let size = 3;
-        let memory = self.memory.read().unwrap();
-        self.reg_d = memory[self.pc + 2];
-        self.reg_e = memory[self.pc + 1];
-        self.print_disassembly(format!("LD DE,${:0>2X}{:0>2X}", self.reg_d, self.reg_e),
-                               size);
-        size
-    }
-
-    fn ld_hl_a(&mut self) -> u16 {
-        let size = 1;
-        let address = self.hl();
-        self.print_disassembly(format!("LD [HL], A ; HL=0x{:0>4X}, A={:0>2X}", address, self.reg_a), size);
-        let mut memory = self.memory.write().unwrap();
-        memory[address] = self.reg_a;
-        size
-    }
-
-    fn inc(&mut self, opcode: u8) -> u16 {
-        let size = 1;
-        match opcode {
-            0x04 => {
-                self.reg_b = self.reg_b.wrapping_add(1);
-                self.print_disassembly(format!("INC B"), size);
-            },
-            0x0C => {
-                self.reg_c = self.reg_c.wrapping_add(1);
-                self.print_disassembly(format!("INC C"), size);
-            },
-            _ => panic!("tried to increment an unknown register, opcode {}", opcode)
+    fn illegal(&mut self, opcode: u8) {
+        let address = self.pc.wrapping_sub(1);
+        let message = format!("illegal opcode {:0>2X} at {:0>4X}", opcode, address);
+        match self.illegal_policy {
+            IllegalPolicy::Lock => {
+                println!("{}, locking up", message);
+                self.locked = true;
+            }
+            IllegalPolicy::Break => {
+                println!("{}\n{}press enter to continue", message, self);
+                let mut line = String::new();
+                io::stdin().read_line(&mut line).unwrap();
+                self.trace = true;
+            }
+            IllegalPolicy::Abort => self.crash(address, opcode, message),
         }
-        //print_disassembly(format!("INC {}", from), size);
-        size
     }
 
-    fn ldr_c_a(&mut self) -> u16 {
-        let size = 1;
-        let address = 0xFF00 + self.reg_c as u16;
-        self.print_disassembly(format!("LD [C],A; C=+${:0>2X}, A={:0>2X}", self.reg_c, self.reg_a),
-                               size);
-        let mut memory = self.memory.write().unwrap();
-        memory[address] = self.reg_a;
-        size
+    pub fn get(&self, flag: Flag) -> bool {
+        self.reg_f & flag as u8 != 0
     }
 
-    fn ld_a16_a(&mut self) -> u16 {
-        let size = 3;
-        let address = self.read_word(self.pc + 1);
-        self.print_disassembly(format!("LD ${:0>4X}, {:0>2X}", address, self.reg_a), size);
-        let mut memory = self.memory.write().unwrap();
-        memory[address] = self.reg_a;
-        size
+    pub fn set(&mut self, flag: Flag, set: bool) {
+        if set {
+            self.reg_f |= flag as u8;
+        } else {
+            self.reg_f &= !(flag as u8);
+        }
     }
 
-    fn print_disassembly(&self, instruction: String, num_bytes: u16) {
-        let mut s = String::new();
-
-        let memory = self.memory.read().unwrap();
-        for &byte in &memory[self.pc..self.pc + num_bytes] {
-            write!(&mut s, "0x{:0>2X} ", byte).unwrap();
+    fn check(&self, condition: Option<Condition>) -> bool {
+        match condition {
+            None => true,
+            Some(Condition::NZ) => !self.get(Flag::ZERO),
+            Some(Condition::Z) => self.get(Flag::ZERO),
+            Some(Condition::NC) => !self.get(Flag::CARRY),
+            Some(Condition::C) => self.get(Flag::CARRY),
         }
-        println!("[0x{:0>8X}] {:<15} {:<32} {:>16X}",
-                 self.pc,
-                 s,
-                 instruction,
-                 self.operations)
     }
 
-    // OPERATIONS START HERE
-
-    fn ret(&mut self) -> u16 {
-        let memory = { self.memory.read().unwrap() };
-        let addr_h = memory[self.sp.wrapping_add(1)];
-        let addr_l = memory[self.sp.wrapping_add(2)];
-        let return_address = (addr_h as u16) << 8 | addr_l as u16;
-        self.print_disassembly(format!("RET ({:0>4X})", return_address), 1);
-
-        self.pc = return_address;
-
-        // move back "up" the stack, zeroing out
-        self.sp = self.sp.wrapping_add(1);
-        self.sp = 0x00;
-        self.sp = self.sp.wrapping_add(1);
-        self.sp = 0x00;
+    // Adjust A back into binary coded decimal after an addition or subtraction
+    fn daa(&mut self) {
+        let mut carry = self.get(Flag::CARRY);
+        let mut adjust = 0;
 
-        0
-    }
+        if self.get(Flag::SUBTRACT) {
+            if carry {
+                adjust |= 0x60;
+            }
+            if self.get(Flag::HALFCARRY) {
+                adjust |= 0x06;
+            }
+            self.reg_a = self.reg_a.wrapping_sub(adjust);
+        } else {
+            if carry || self.reg_a > 0x99 {
+                adjust |= 0x60;
+                carry = true;
+            }
+            if self.get(Flag::HALFCARRY) || self.reg_a & 0x0F > 0x09 {
+                adjust |= 0x06;
+            }
+            self.reg_a = self.reg_a.wrapping_add(adjust);
+        }
 
-    fn halt(&mut self) -> u16 {
-        self.running = false;
-        0
+        let zero = self.reg_a == 0;
+        self.set(Flag::ZERO, zero);
+        self.set(Flag::HALFCARRY, false);
+        self.set(Flag::CARRY, carry);
     }
 
-    // when we jump to a new address, make sure to save the current program counter
-    // address to the bottom of the stack, so when we can return to the current address
-    //
-    fn jmp_a16(&mut self) -> u16 {
-        let address = self.read_word(self.pc.wrapping_add(1));
-        self.print_disassembly(format!("JMP {:0>4X}", address), 3);
-        self.pc = address;
-        0
+    fn push_word(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(1);
+        let sp = self.sp;
+        self.write(sp, (value >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        let sp = self.sp;
+        self.write(sp, value as u8);
     }
 
-    fn ld_hl_d16(&mut self) -> u16 {
-        let size = 3;
-        let memory = self.memory.read().unwrap();
-        self.reg_l = memory[self.pc + 1];
-        self.reg_h = memory[self.pc + 2];
-        self.print_disassembly(format!("LD HL,${:0>2X}{:0>2X}", self.reg_h, self.reg_l),
-                               size);
-        size
+    fn pop_word(&mut self) -> u16 {
+        let value = self.read_word(self.sp);
+        self.sp = self.sp.wrapping_add(2);
+        value
     }
 
-    fn ld_sp_d16(&mut self) -> u16 {
-        let size = 3;
-        let address = self.read_word(self.pc + 1);
-        self.print_disassembly(format!("LD SP,${:0>4X}", address), size);
-        self.sp = address;
-        size
-    }
+    fn inc(&mut self, to: Operand) {
+        let value = self.operand(to);
+        let result = value.wrapping_add(1);
+        self.set_operand(to, result);
 
-    fn de(&self) -> u16 {
-        ((self.reg_d as u16) << 8) + ((self.reg_e as u16) << 0)
+        self.set(Flag::ZERO, result == 0);
+        self.set(Flag::SUBTRACT, false);
+        self.set(Flag::HALFCARRY, value & 0x0F == 0x0F);
     }
 
-    fn hl(&self) -> u16 {
-        ((self.reg_h as u16) << 8) + ((self.reg_l as u16) << 0)
-    }
+    fn dec(&mut self, to: Operand) {
+        let value = self.operand(to);
+        let result = value.wrapping_sub(1);
+        self.set_operand(to, result);
 
-    fn ldi_hl_a(&mut self) -> u16 {
-        let size = 1;
-        self.print_disassembly(format!("LD (HL+) ({:0>2X}{:0>2X}), {:?}",
-                                       self.reg_h,
-                                       self.reg_l,
-                                       self.reg_a),
-                               size);
-        let address = self.hl();
-        self.memory.write().unwrap()[address] = self.reg_a;
-        self.store_hl(address.wrapping_add(1));
-        size
+        self.set(Flag::ZERO, result == 0);
+        self.set(Flag::SUBTRACT, true);
+        self.set(Flag::HALFCARRY, value & 0x0F == 0);
     }
 
-    fn store_hl(&mut self, address: u16) {
-        self.reg_l = (address >> 0) as u8;
-        self.reg_h = (address >> 8) as u8;
-    }
+    fn add_hl(&mut self, from: Reg16) {
+        let hl = self.hl();
+        let value = self.reg16(from);
+        let result = hl as u32 + value as u32;
 
-    fn store_de(&mut self, address: u16) {
-        self.reg_e = (address >> 0) as u8;
-        self.reg_d = (address >> 8) as u8;
+        self.set(Flag::SUBTRACT, false);
+        self.set(Flag::HALFCARRY, (hl & 0x0FFF) + (value & 0x0FFF) > 0x0FFF);
+        self.set(Flag::CARRY, result > 0xFFFF);
+        self.store_hl(result as u16);
     }
 
-    fn ldd_hl_a(&mut self) -> u16 {
-        let size = 1;
-        self.print_disassembly(format!("LD [HLD],A ; HL=({:0>2X}{:0>2X}), A={:?}",
-                                       self.reg_h,
-                                       self.reg_l,
-                                       self.reg_a),
-                               size);
+    // SP plus a signed immediate, with the flags of an unsigned add on the low byte
+    fn sp_plus(&mut self, offset: i8) -> u16 {
+        let sp = self.sp;
+        let low = offset as u8 as u16;
 
-        let address = self.hl();
-        self.memory.write().unwrap()[address] = self.reg_a;
-
-        self.store_hl(address.wrapping_sub(1));
-        size
-    }
-
-    fn nop(&self) -> u16 {
-        let size = 1;
-        self.print_disassembly(format!("NOP"), size);
-        size
+        self.reg_f = 0;
+        self.set(Flag::HALFCARRY, (sp & 0x0F) + (low & 0x0F) > 0x0F);
+        self.set(Flag::CARRY, (sp & 0xFF) + low > 0xFF);
+        sp.wrapping_add(offset as u16)
     }
 
-    fn jr_r8(&mut self) -> u16 {
-        let size = 2;
-        let memory = self.memory.read().unwrap();
-        let offset = memory[self.pc + 1] as i8;
-        let address = self.pc.wrapping_add(offset as u16);
-        self.print_disassembly(format!("JR $+{:0>2X} ; 0x{:0>4X}", offset, address + 1),
-                               size);
-
-        self.pc = address;
-        size
-    }
+    // Thank you https://realboyemulator.wordpress.com/2013/01/03/a-look-at-the-game-boy-bootstrap-let-the-fun-begin/comment-page-1/
+    fn alu(&mut self, operation: Alu, value: u8) {
+        let a = self.reg_a;
+        let carry = self.get(Flag::CARRY) as u8;
 
-    fn jr(&mut self, flag: Flag, zero: bool) -> u16 {
-        let size = 2;
-        let memory = self.memory.read().unwrap();
-        let offset = memory[self.pc + 1] as i8;
-        let address = self.pc.wrapping_add(offset as u16);
-        let n = if zero {
-            ""
-        } else {
-            "N"
+        let (result, half, carry) = match operation {
+            Alu::Add => {
+                (a.wrapping_add(value),
+                 (a & 0x0F) + (value & 0x0F) > 0x0F,
+                 a as u16 + value as u16 > 0xFF)
+            }
+            Alu::Adc => {
+                (a.wrapping_add(value).wrapping_add(carry),
+                 (a & 0x0F) + (value & 0x0F) + carry > 0x0F,
+                 a as u16 + value as u16 + carry as u16 > 0xFF)
+            }
+            Alu::Sub | Alu::Cp => {
+                (a.wrapping_sub(value),
+                 a & 0x0F < value & 0x0F,
+                 a < value)
+            }
+            Alu::Sbc => {
+                (a.wrapping_sub(value).wrapping_sub(carry),
+                 a & 0x0F < (value & 0x0F) + carry,
+                 (a as u16) < value as u16 + carry as u16)
+            }
+            Alu::And => (a & value, true, false),
+            Alu::Xor => (a ^ value, false, false),
+            Alu::Or => (a | value, false, false),
         };
-        self.print_disassembly(format!("JR {}Z, $+{:0>2X} ; 0x{:0>4X}", n, offset, address + 1),
-                               size);
 
-        if self.get(flag) == zero {
-            self.pc = address;
+        self.set(Flag::ZERO, result == 0);
+        self.set(Flag::SUBTRACT, match operation {
+            Alu::Sub | Alu::Sbc | Alu::Cp => true,
+            _ => false,
+        });
+        self.set(Flag::HALFCARRY, half);
+        self.set(Flag::CARRY, carry);
+
+        if operation != Alu::Cp {
+            self.reg_a = result;
         }
-        size
     }
 
-    fn bit_h(&mut self, bit: u8) -> u16 {
-        let size = 1;
-        let h = self.reg_h;
-        let zero = h & (1 << bit) == 0;
+    fn shift(&mut self, operation: Shift, value: u8) -> u8 {
+        let carry = self.get(Flag::CARRY) as u8;
 
-        self.set(Flag::ZERO, zero);
-        self.set(Flag::SUBTRACT, false);
-        self.set(Flag::HALFCARRY, true);
+        let (result, carry) = match operation {
+            Shift::Rlc => (value.rotate_left(1), value & 0b10000000 != 0),
+            Shift::Rrc => (value.rotate_right(1), value & 0b00000001 != 0),
+            Shift::Rl => (value << 1 | carry, value & 0b10000000 != 0),
+            Shift::Rr => (value >> 1 | carry << 7, value & 0b00000001 != 0),
+            Shift::Sla => (value << 1, value & 0b10000000 != 0),
+            Shift::Sra => (value >> 1 | value & 0b10000000, value & 0b00000001 != 0),
+            Shift::Swap => (value.rotate_left(4), false),
+            Shift::Srl => (value >> 1, value & 0b00000001 != 0),
+        };
 
-        self.print_disassembly(format!("BIT {}, H; {}", bit, zero), size);
-        size
+        self.reg_f = 0;
+        self.set(Flag::ZERO, result == 0);
+        self.set(Flag::CARRY, carry);
+        result
     }
 
-    // Thank you https://realboyemulator.wordpress.com/2013/01/03/a-look-at-the-game-boy-bootstrap-let-the-fun-begin/comment-page-1/
-    fn xor_a(&mut self) -> u16 {
-        let size = 1;
-        self.print_disassembly(format!("XOR A"), size);
-        self.reg_a ^= self.reg_a;
-        size
+    fn rotate_a(&mut self, operation: Shift) {
+        let a = self.reg_a;
+        self.reg_a = self.shift(operation, a);
+        self.set(Flag::ZERO, false);
     }
 
-    fn ldh_a8_a(&mut self) -> u16 {
-        let size = 2;
-
-        let mut offset = 0;
-
-        if let Ok(mut memory) = self.memory.write() {
-            offset = memory[self.pc + 1];
+    fn print_disassembly(&self, address: u16, instruction: Instruction) {
+        let mut s = String::new();
 
-            let address = 0xFF00 + offset as u16;
-            memory[address] = self.reg_a;
+        for offset in 0..instruction.length() {
+            write!(&mut s, "0x{:0>2X} ", self.peek(address.wrapping_add(offset))).unwrap();
         }
-
-        self.print_disassembly(format!("LDH (${:0>2X}), A", offset), size);
-
-        size
+        println!("[0x{:0>8X}] {:<15} {:<32} {:>16X}",
+                 address,
+                 s,
+                 instruction.to_string(),
+                 self.operations)
     }
 
-    fn ldh_a_a8(&mut self) -> u16 {
-        let size = 2;
-        let memory = self.memory.read().unwrap();
-        let offset = memory[self.pc + 1];
-        self.print_disassembly(format!("LDH A, (${:0>2X})", offset), size);
-
-        let address = 0xFF00 + offset as u16;
-        let value = memory[address];
-        self.reg_a = value;
+    // With IME off and an interrupt already pending, HALT exits immediately
+    // and the DMG reads the byte after it twice
+    fn halt(&mut self) {
+        let pending = self.interrupt_enable() & self.interrupt_flag() != 0;
+        if !self.ime && pending {
+            self.halt_bug = true;
+        } else {
+            self.halted = true;
+        }
+    }
 
-        size
+    // Buttons pull their P1 line (bits 0-3 of 0xFF00) low
+    fn joypad_pressed(&self) -> bool {
+        self.peek(0xFF00) & 0b00001111 != 0b00001111
     }
 
-    fn pop_bc(&mut self) -> u16 {
-        let size = 1;
-        self.print_disassembly(format!("POP BC"), size);
-        self.sp += 1;
-        let mut memory = self.memory.write().unwrap();
-        self.reg_b = memory[self.sp];
-        memory[self.sp] = 0;
+    fn hl(&self) -> u16 {
+        self.reg16(Reg16::HL)
+    }
 
-        self.sp += 1;
-        self.reg_c = memory[self.sp];
-        memory[self.sp] = 0;
-        size
+    fn store_hl(&mut self, value: u16) {
+        self.set_reg16(Reg16::HL, value);
     }
 
-    fn pop_hl(&mut self) -> u16 {
-        let size = 1;
-        self.print_disassembly(format!("POP HL"), size);
-        self.sp += 1;
-        let mut memory = self.memory.write().unwrap();
-        self.reg_h = memory[self.sp];
-        memory[self.sp] = 0;
-        self.sp += 1;
-        self.reg_l = memory[self.sp];
-        memory[self.sp] = 0;
-        size
+    // The HL+ and HL- forms adjust HL after using it
+    fn address(&mut self, address: Address) -> u16 {
+        match address {
+            Address::BC => self.reg16(Reg16::BC),
+            Address::DE => self.reg16(Reg16::DE),
+            Address::HLIncrement => {
+                let hl = self.hl();
+                self.store_hl(hl.wrapping_add(1));
+                hl
+            }
+            Address::HLDecrement => {
+                let hl = self.hl();
+                self.store_hl(hl.wrapping_sub(1));
+                hl
+            }
+            Address::High(offset) => 0xFF00 + offset as u16,
+            Address::HighC => 0xFF00 + self.reg_c as u16,
+            Address::Absolute(address) => address,
+        }
     }
 
-    fn reg(&self, from: &'static str) -> u8 {
+    fn reg(&self, from: Reg8) -> u8 {
         match from {
-            "a" => self.reg_a,
-            "b" => self.reg_b,
-            "c" => self.reg_c,
-            "d" => self.reg_d,
-            "e" => self.reg_e,
-            "h" => self.reg_h,
-            "l" => self.reg_l,
-            _ => panic!("'{}' does not match a register", from)
+            Reg8::A => self.reg_a,
+            Reg8::B => self.reg_b,
+            Reg8::C => self.reg_c,
+            Reg8::D => self.reg_d,
+            Reg8::E => self.reg_e,
+            Reg8::H => self.reg_h,
+            Reg8::L => self.reg_l,
         }
     }
 
-    fn dec(&mut self, from: &'static str) -> u16 {
-        let size = 1;
-        self.print_disassembly(format!("DEC {}", from), size);
-
-        let half = self.reg(from) == 0;
-        self.set(Flag::HALFCARRY, half);
+    fn set_reg(&mut self, to: Reg8, value: u8) {
+        match to {
+            Reg8::A => self.reg_a = value,
+            Reg8::B => self.reg_b = value,
+            Reg8::C => self.reg_c = value,
+            Reg8::D => self.reg_d = value,
+            Reg8::E => self.reg_e = value,
+            Reg8::H => self.reg_h = value,
+            Reg8::L => self.reg_l = value,
+        }
+    }
 
+    fn operand(&self, from: Operand) -> u8 {
         match from {
-            "a" => self.reg_a = self.reg_a.wrapping_sub(1),
-            "b" => self.reg_b = self.reg_b.wrapping_sub(1),
-            "c" => self.reg_c = self.reg_c.wrapping_sub(1),
-            "d" => self.reg_d = self.reg_d.wrapping_sub(1),
-            "e" => self.reg_e = self.reg_e.wrapping_sub(1),
-            "h" => self.reg_h = self.reg_h.wrapping_sub(1),
-            "l" => self.reg_l = self.reg_l.wrapping_sub(1),
-            _ => panic!("'{}' does not match a register", from)
-        };
-
-        let zero = self.reg(from) == 0;
-        self.set(Flag::ZERO, zero);
-        self.set(Flag::SUBTRACT, true);
-        size
+            Operand::Reg(reg) => self.reg(reg),
+            Operand::IndirectHL => self.read(self.hl()),
+            Operand::Immediate(value) => value,
+        }
     }
 
-    fn inc_de(&mut self) -> u16 {
-        let size = 1;
-        self.print_disassembly(format!("INC DE"), size);
-        let value = self.de();
-        self.store_de(value.wrapping_add(1));
-        size
+    fn set_operand(&mut self, to: Operand, value: u8) {
+        match to {
+            Operand::Reg(reg) => self.set_reg(reg, value),
+            Operand::IndirectHL => {
+                let address = self.hl();
+                self.write(address, value);
+            }
+            Operand::Immediate(_) => panic!("cannot write {:0>2X} to an immediate", value),
+        }
     }
 
-    fn inc_hl(&mut self) -> u16 {
-        let size = 1;
-        self.print_disassembly(format!("INC HL"), size);
-        let value = self.hl();
-        self.store_hl(value.wrapping_add(1));
-        size
+    fn reg16(&self, from: Reg16) -> u16 {
+        let (high, low) = match from {
+            Reg16::AF => (self.reg_a, self.reg_f),
+            Reg16::BC => (self.reg_b, self.reg_c),
+            Reg16::DE => (self.reg_d, self.reg_e),
+            Reg16::HL => (self.reg_h, self.reg_l),
+            Reg16::SP => return self.sp,
+        };
+        (high as u16) << 8 | low as u16
     }
 
-    fn cp_d8(&mut self) -> u16 {
-        let size = 2;
-        let a = self.reg_a;
-        let value = self.memory.read().unwrap()[self.pc + 1];
-        self.print_disassembly(format!("CP 0x{:0>2X}", value), size);
-        self.set(Flag::ZERO, a == value);
-        self.set(Flag::SUBTRACT, true);
-        self.set(Flag::HALFCARRY, (a << 4) < (value << 4));
-        self.set(Flag::CARRY, a < value);
-        size
+    fn set_reg16(&mut self, to: Reg16, value: u16) {
+        let high = (value >> 8) as u8;
+        let low = value as u8;
+        match to {
+            // The low nibble of F is always zero
+            Reg16::AF => {
+                self.reg_a = high;
+                self.reg_f = low & 0xF0;
+            }
+            Reg16::BC => {
+                self.reg_b = high;
+                self.reg_c = low;
+            }
+            Reg16::DE => {
+                self.reg_d = high;
+                self.reg_e = low;
+            }
+            Reg16::HL => {
+                self.reg_h = high;
+                self.reg_l = low;
+            }
+            Reg16::SP => self.sp = value,
+        }
     }
 }
 
-impl fmt::Display for Cpu {
+impl<M: Bus> fmt::Display for Cpu<M> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let memory = self.memory.read().unwrap();
         try!(writeln!(f,
@@ -877,10 +955,10 @@ impl fmt::Display for Cpu {
                    }}\n}}
             ",
                       pc = self.pc,
-                      i0 = memory[self.pc + 0],
-                      i1 = memory[self.pc + 1],
-                      i2 = memory[self.pc + 2],
-                      i3 = memory[self.pc + 3],
+                      i0 = memory.peek(self.pc.wrapping_add(0)),
+                      i1 = memory.peek(self.pc.wrapping_add(1)),
+                      i2 = memory.peek(self.pc.wrapping_add(2)),
+                      i3 = memory.peek(self.pc.wrapping_add(3)),
                       sp = self.sp,
                       a = self.reg_a,
                       f = self.reg_f,
@@ -0,0 +1,61 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use cpu::Registers;
+use instruction::Instruction;
+
+/// Everything worth knowing about the cpu when it gave up, for bug reports
+#[derive(Clone, Debug)]
+pub struct CrashReport {
+    pub reason: String,
+    pub address: u16,
+    pub opcode: u8,
+    pub registers: Registers,
+    // the last instructions executed, oldest first, ending with the one that crashed
+    pub history: Vec<(u16, Instruction)>,
+    // up to 64 bytes from SP towards the top of the stack at 0xFFFE
+    pub stack: Vec<u8>,
+    pub rom_bank: usize,
+    // 0xFF00 to 0xFF7F
+    pub io: Vec<u8>,
+    pub interrupt_enable: u8,
+}
+
+impl CrashReport {
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut file = try!(fs::File::create(path));
+        write!(file, "{}", self)
+    }
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let r = &self.registers;
+        try!(writeln!(f, "crash: {}", self.reason));
+        try!(writeln!(f, "opcode {:0>2X} at {:0>4X}, rom bank {}", self.opcode, self.address, self.rom_bank));
+        try!(writeln!(f,
+                      "pc: {:0>4X} sp: {:0>4X} a: {:0>2X} f: {:0>2X} b: {:0>2X} c: {:0>2X} d: {:0>2X} e: {:0>2X} \
+                       h: {:0>2X} l: {:0>2X} ime: {}",
+                      r.pc, r.sp, r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l, r.ime));
+
+        try!(writeln!(f, "\nhistory:"));
+        for &(address, instruction) in &self.history {
+            try!(writeln!(f, "  {:0>4X}: {}", address, instruction));
+        }
+
+        try!(writeln!(f, "\nstack:"));
+        for (offset, byte) in self.stack.iter().enumerate() {
+            try!(writeln!(f, "  {:0>4X}: {:0>2X}", r.sp.wrapping_add(offset as u16), byte));
+        }
+
+        try!(writeln!(f, "\nio:"));
+        for (row, bytes) in self.io.chunks(16).enumerate() {
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:0>2X}", b)).collect();
+            try!(writeln!(f, "  {:0>4X}: {}", 0xFF00 + row * 16, hex.join(" ")));
+        }
+        writeln!(f, "  FFFF: {:0>2X}", self.interrupt_enable)
+    }
+}
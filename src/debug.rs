@@ -35,7 +35,9 @@ impl window::Drawable for DebugScreen {
 
     fn draw(&mut self) {
         let offset = self.scroll.wrapping_sub(self.offset);
-        let byte = { self.memory.read().unwrap()[offset] };
+        // the whole address space at once, without the ppu or DMA getting in the way
+        let bytes = { self.memory.read().unwrap().read_range(0x0000..=0xFFFF) };
+        let byte = bytes[offset as usize];
         let s = format!("0x{:0>4X}: {:0>4X}: {:0>2X}",
                         self.scroll,
                         offset,
@@ -44,10 +46,8 @@ impl window::Drawable for DebugScreen {
 
 
         let mut count = self.scroll;
-        let memory = { self.memory.read().unwrap() };
-
         for i in &mut self.buffer {
-            let gray = memory[count] as u32;
+            let gray = bytes[count as usize] as u32;
             *i = gray << 16 | gray << 8 | gray;
             count = count.wrapping_sub(1);
         }
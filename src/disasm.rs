@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::iter;
+use std::fmt::Write;
+
+use instruction;
+use instruction::Instruction;
+use memory::Bus;
+
+pub const BANK_SIZE: usize = 0x4000;
+
+// The cartridge header is data, so it is dumped as bytes rather than decoded
+const HEADER: (usize, usize) = (0x0104, 0x0150);
+
+/// One decoded instruction, and where it lives
+#[allow(dead_code)]
+pub struct Line {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub instruction: Instruction,
+    pub mnemonic: &'static str,
+    pub operands: Vec<String>,
+    pub length: u16,
+    pub cycles: u8,
+    // clocks taken when a conditional branch is followed
+    pub cycles_taken: u8,
+}
+
+impl Line {
+    /// Where a jump, call or restart transfers control to
+    pub fn target(&self) -> Option<u16> {
+        let next = self.address.wrapping_add(self.length);
+        match self.instruction {
+            Instruction::Jr(_, offset) => Some(next.wrapping_add(offset as u16)),
+            Instruction::Jp(_, address) | Instruction::Call(_, address) => Some(address),
+            Instruction::Rst(vector) => Some(vector),
+            _ => None,
+        }
+    }
+
+    fn is_call(&self) -> bool {
+        match self.instruction {
+            Instruction::Call(..) | Instruction::Rst(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.instruction)
+    }
+}
+
+/// Decode the instruction at the start of `bytes`, which are mapped at `address`.
+/// Bytes past the end of the slice read as zero
+pub fn decode(bytes: &[u8], address: u16) -> Line {
+    let mut read = 0;
+    let instruction = instruction::decode(|| {
+        let byte = bytes.get(read).cloned().unwrap_or(0);
+        read += 1;
+        byte
+    });
+
+    line(address, instruction, bytes.iter().cloned().chain(iter::repeat(0)).take(read).collect())
+}
+
+/// Decode the instruction at `address` in the memory map, without hooks or the ppu noticing
+#[allow(dead_code)]
+pub fn decode_at(memory: &Bus, address: u16) -> Line {
+    let mut bytes = Vec::new();
+    let instruction = instruction::decode(|| {
+        let byte = memory.peek(address.wrapping_add(bytes.len() as u16));
+        bytes.push(byte);
+        byte
+    });
+
+    line(address, instruction, bytes)
+}
+
+fn line(address: u16, instruction: Instruction, bytes: Vec<u8>) -> Line {
+    let mut operands = instruction.operands();
+
+    // show relative jumps by their destination
+    if let Instruction::Jr(..) = instruction {
+        let offset = bytes[1] as i8 as u16;
+        let destination = address.wrapping_add(2).wrapping_add(offset);
+        *operands.last_mut().unwrap() = format!("${:0>4X}", destination);
+    }
+
+    Line {
+        address: address,
+        bytes: bytes,
+        instruction: instruction,
+        mnemonic: instruction.mnemonic(),
+        operands: operands,
+        length: instruction.length(),
+        cycles: instruction.cycles(false),
+        cycles_taken: instruction.cycles(true),
+    }
+}
+
+/// Linearly decode every instruction in `bytes`, which are mapped at `address`
+#[allow(dead_code)]
+pub fn disassemble(bytes: &[u8], address: u16) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        let line = decode(&bytes[offset..], address.wrapping_add(offset as u16));
+        offset += line.length as usize;
+        lines.push(line);
+    }
+    lines
+}
+
+// Bank 0 is always at 0x0000, every other bank is switched in at 0x4000
+fn origin(bank: usize) -> u16 {
+    if bank == 0 { 0x0000 } else { 0x4000 }
+}
+
+// Which bank a target seen from `bank` lands in, if it is in ROM at all
+fn target_bank(bank: usize, target: u16) -> Option<usize> {
+    match target {
+        0x0000...0x3FFF => Some(0),
+        0x4000...0x7FFF => Some(if bank == 0 { 1 } else { bank }),
+        _ => None,
+    }
+}
+
+fn is_data(bank: usize, address: usize) -> bool {
+    bank == 0 && address >= HEADER.0 && address < HEADER.1
+}
+
+// Decode one bank, leaving the header and any instruction cut off by the end of the bank as data
+fn disassemble_bank(rom: &[u8], bank: usize) -> Vec<Result<Line, (u16, Vec<u8>)>> {
+    let start = bank * BANK_SIZE;
+    let bank_rom = &rom[start..rom.len().min(start + BANK_SIZE)];
+    let mut lines = Vec::new();
+    let mut offset = 0;
+
+    while offset < bank_rom.len() {
+        let address = origin(bank).wrapping_add(offset as u16);
+
+        if is_data(bank, offset) {
+            let end = HEADER.1.min(offset + 16).min(bank_rom.len());
+            lines.push(Err((address, bank_rom[offset..end].to_vec())));
+            offset = end;
+            continue;
+        }
+
+        let line = decode(&bank_rom[offset..], address);
+        let end = offset + line.length as usize;
+        if end > bank_rom.len() || (is_data(bank, end - 1) && !is_data(bank, offset)) {
+            let data_end = if end > bank_rom.len() { bank_rom.len() } else { HEADER.0 };
+            lines.push(Err((address, bank_rom[offset..data_end].to_vec())));
+            offset = data_end;
+            continue;
+        }
+
+        offset = end;
+        lines.push(Ok(line));
+    }
+    lines
+}
+
+/// Disassemble every bank of a ROM, labelling the targets of jumps and calls
+pub fn disassemble_rom(rom: &[u8]) -> String {
+    let banks = (rom.len() + BANK_SIZE - 1) / BANK_SIZE;
+    let decoded: Vec<_> = (0..banks).map(|bank| disassemble_bank(rom, bank)).collect();
+
+    let mut labels = BTreeMap::new();
+    for (bank, lines) in decoded.iter().enumerate() {
+        for line in lines.iter().filter_map(|line| line.as_ref().ok()) {
+            if let Some(target) = line.target() {
+                if let Some(target_bank) = target_bank(bank, target) {
+                    let kind = if line.is_call() { "call" } else { "jump" };
+                    let name = format!("{}_{:0>3X}_{:0>4X}", kind, target_bank, target);
+                    // a call anywhere names the target a call, even if it is also jumped to
+                    let label = labels.entry((target_bank, target)).or_insert(name.clone());
+                    if kind == "call" {
+                        *label = name;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut out = String::new();
+    for (bank, lines) in decoded.iter().enumerate() {
+        let section = if bank == 0 {
+            "ROM0[$0000]".to_string()
+        } else {
+            format!("ROMX[$4000], BANK[${:X}]", bank)
+        };
+        writeln!(out, "\nSECTION \"ROM Bank ${:0>3X}\", {}\n", bank, section).unwrap();
+
+        for line in lines {
+            let (address, text, bytes) = match *line {
+                Ok(ref line) => {
+                    let mut operands = line.operands.clone();
+                    let label = line.target()
+                        .and_then(|target| target_bank(bank, target).map(|b| (b, target)))
+                        .and_then(|key| labels.get(&key));
+                    if let Some(label) = label {
+                        *operands.last_mut().unwrap() = label.clone();
+                    }
+                    let text = if operands.is_empty() {
+                        line.mnemonic.to_string()
+                    } else {
+                        format!("{} {}", line.mnemonic, operands.join(", "))
+                    };
+                    (line.address, text, &line.bytes)
+                }
+                Err((address, ref bytes)) => {
+                    let values: Vec<String> = bytes.iter().map(|b| format!("${:0>2X}", b)).collect();
+                    (address, format!("DB {}", values.join(", ")), bytes)
+                }
+            };
+
+            if let Some(label) = labels.get(&(bank, address)) {
+                writeln!(out, "{}:", label).unwrap();
+            }
+
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:0>2X}", b)).collect();
+            writeln!(out, "    {:<32} ; ${:0>4X}: {}", text, address, hex.join(" ")).unwrap();
+        }
+    }
+    out
+}
+
+#[test]
+fn relative_jumps_show_their_destination() {
+    let line = decode(&[0x20, 0xFE], 0x0150);
+    assert_eq!(line.mnemonic, "JR");
+    assert_eq!(line.operands, vec!["NZ".to_string(), "$0150".to_string()]);
+    assert_eq!(line.target(), Some(0x0150));
+    assert_eq!((line.length, line.cycles, line.cycles_taken), (2, 8, 12));
+}
+
+#[test]
+fn labels_call_targets() {
+    let mut rom = vec![0; BANK_SIZE * 2];
+    rom[0x0000] = 0xCD; // CALL $4000
+    rom[0x0002] = 0x40;
+    rom[0x4000] = 0xC9; // RET
+
+    let listing = disassemble_rom(&rom);
+    assert!(listing.contains("CALL call_001_4000"));
+    assert!(listing.contains("call_001_4000:\n    RET"));
+}
@@ -1,20 +1,94 @@
 use std::sync::{Arc, RwLock};
 
 use cpu;
+use crash;
 use lcd;
 use cart;
 use memory;
 
 pub const BOOTROM_SIZE: usize = 256;
 
-pub fn run(boot: [u8; BOOTROM_SIZE], cart: cart::Cart) {
-    let memory = Arc::new(RwLock::new(memory::Memory::new(boot, cart)));
+// The dmg runs at 4194304 clocks a second
+pub const CLOCK: u64 = 4194304;
+
+// The hardware models, which the boot rom leaves in slightly different states
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Model {
+    Dmg,
+    Mgb,
+    Sgb,
+    Cgb,
+}
+
+impl Model {
+    pub fn from_name(name: &str) -> Option<Model> {
+        match name.to_lowercase().as_str() {
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "sgb" => Some(Model::Sgb),
+            "cgb" => Some(Model::Cgb),
+            _ => None,
+        }
+    }
+}
+
+/// Things the emulated hardware does that a frontend can react to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    // a rumble cart turned its motor on or off
+    Rumble(bool),
+}
+
+/// Without a boot rom, the cpu and io registers start where the boot rom would leave them.
+/// Without `ppu_blocking`, the cpu can reach vram and oam in every ppu mode.
+/// Every `Event` is handed to `on_event` as it happens. Runs until the cpu crashes
+pub fn run<F>(boot: Option<[u8; BOOTROM_SIZE]>,
+              cart: cart::Cart,
+              model: Model,
+              illegal: cpu::IllegalPolicy,
+              ppu_blocking: bool,
+              mut on_event: F)
+              -> Option<crash::CrashReport>
+    where F: FnMut(Event)
+{
+    let image = boot.unwrap_or([0; BOOTROM_SIZE]);
+    let memory = Arc::new(RwLock::new(memory::Memory::new(image, cart)));
+    memory.write().unwrap().set_model(model);
+    memory.write().unwrap().set_ppu_blocking(ppu_blocking);
 
     let mut cpu = cpu::Cpu::new(memory.clone());
-    let mut lcd = lcd::LcdScreen::new(160, 144, memory.clone());
+    cpu.set_illegal_policy(illegal);
+    // the ppu keeps its own time in memory, the screen only shows it
+    let _lcd = lcd::LcdScreen::new(160, 144, memory.clone());
+
+    if boot.is_none() {
+        memory.write().unwrap().skip_boot(model);
+        cpu.skip_boot(model);
+    }
+
+    let mut clocks = 0;
+    while cpu.running() {
+        let cycles = cpu.step();
+
+        // save battery backed ram every second, so little is lost however the emulator stops
+        clocks += cycles as u64;
+        if clocks >= CLOCK {
+            clocks -= CLOCK;
+            save(&memory);
+        }
+
+        for event in memory.write().unwrap().take_events() {
+            on_event(event);
+        }
+    }
+
+    save(&memory);
+    cpu.crash_report().cloned()
+}
 
-    loop {
-        cpu.step();
-        lcd.step();
+// A save that can't be written is worth a warning, not losing the game in progress too
+fn save(memory: &Arc<RwLock<memory::Memory>>) {
+    if let Err(error) = memory.write().unwrap().flush() {
+        println!("could not write the save file: {}", error);
     }
 }
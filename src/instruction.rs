@@ -0,0 +1,507 @@
+use std::fmt;
+
+use self::Instruction::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reg8 {
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    A,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reg16 {
+    BC,
+    DE,
+    HL,
+    SP,
+    AF,
+}
+
+// Anything an 8-bit instruction can read from or write to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    Reg(Reg8),
+    IndirectHL,
+    Immediate(u8),
+}
+
+// The memory operand of the loads into and out of A
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Address {
+    BC,
+    DE,
+    HLIncrement,
+    HLDecrement,
+    High(u8),
+    HighC,
+    Absolute(u16),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Condition {
+    NZ,
+    Z,
+    NC,
+    C,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Alu {
+    Add,
+    Adc,
+    Sub,
+    Sbc,
+    And,
+    Xor,
+    Or,
+    Cp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shift {
+    Rlc,
+    Rrc,
+    Rl,
+    Rr,
+    Sla,
+    Sra,
+    Swap,
+    Srl,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    Nop,
+    Stop,
+    Halt,
+    Di,
+    Ei,
+    Ld(Operand, Operand),
+    LoadA(Address),
+    StoreA(Address),
+    Ld16(Reg16, u16),
+    StoreSp(u16),
+    LdSpHl,
+    LdHlSp(i8),
+    Inc(Operand),
+    Dec(Operand),
+    Inc16(Reg16),
+    Dec16(Reg16),
+    AddHl(Reg16),
+    AddSp(i8),
+    Alu(Alu, Operand),
+    Rlca,
+    Rrca,
+    Rla,
+    Rra,
+    Daa,
+    Cpl,
+    Scf,
+    Ccf,
+    Jr(Option<Condition>, i8),
+    Jp(Option<Condition>, u16),
+    JpHl,
+    Call(Option<Condition>, u16),
+    Ret(Option<Condition>),
+    Reti,
+    Rst(u16),
+    Push(Reg16),
+    Pop(Reg16),
+    Shift(Shift, Operand),
+    Bit(u8, Operand),
+    Res(u8, Operand),
+    Set(u8, Operand),
+    Illegal(u8),
+}
+
+// Decoding tables, indexed by the opcode bit-fields described in
+// http://www.z80.info/decoding.htm (adjusted for the game boy):
+//
+//   7 6 5 4 3 2 1 0
+//   x x y y y z z z
+//       p p q
+const R: [Operand; 8] = [Operand::Reg(Reg8::B),
+                         Operand::Reg(Reg8::C),
+                         Operand::Reg(Reg8::D),
+                         Operand::Reg(Reg8::E),
+                         Operand::Reg(Reg8::H),
+                         Operand::Reg(Reg8::L),
+                         Operand::IndirectHL,
+                         Operand::Reg(Reg8::A)];
+const RP: [Reg16; 4] = [Reg16::BC, Reg16::DE, Reg16::HL, Reg16::SP];
+const RP2: [Reg16; 4] = [Reg16::BC, Reg16::DE, Reg16::HL, Reg16::AF];
+const CC: [Condition; 4] = [Condition::NZ, Condition::Z, Condition::NC, Condition::C];
+const ALU: [Alu; 8] = [Alu::Add, Alu::Adc, Alu::Sub, Alu::Sbc, Alu::And, Alu::Xor, Alu::Or, Alu::Cp];
+const ROT: [Shift; 8] = [Shift::Rlc,
+                         Shift::Rrc,
+                         Shift::Rl,
+                         Shift::Rr,
+                         Shift::Sla,
+                         Shift::Sra,
+                         Shift::Swap,
+                         Shift::Srl];
+const INDIRECT: [Address; 4] = [Address::BC,
+                                Address::DE,
+                                Address::HLIncrement,
+                                Address::HLDecrement];
+const ACCUMULATOR: [Instruction; 8] = [Rlca, Rrca, Rla, Rra, Daa, Cpl, Scf, Ccf];
+
+/// Decode one instruction, pulling as many bytes as it needs from `next`
+pub fn decode<F: FnMut() -> u8>(mut next: F) -> Instruction {
+    let opcode = next();
+    let x = opcode >> 6;
+    let y = ((opcode >> 3) & 0b111) as usize;
+    let z = opcode & 0b111;
+    let p = y >> 1;
+    let q = y & 1;
+
+    match (x, z) {
+        (0, 0) => {
+            match y {
+                0 => Nop,
+                1 => StoreSp(word(&mut next)),
+                2 => {
+                    next();
+                    Stop
+                }
+                3 => Jr(None, next() as i8),
+                _ => Jr(Some(CC[y - 4]), next() as i8),
+            }
+        }
+        (0, 1) if q == 0 => Ld16(RP[p], word(&mut next)),
+        (0, 1) => AddHl(RP[p]),
+        (0, 2) if q == 0 => StoreA(INDIRECT[p]),
+        (0, 2) => LoadA(INDIRECT[p]),
+        (0, 3) if q == 0 => Inc16(RP[p]),
+        (0, 3) => Dec16(RP[p]),
+        (0, 4) => Inc(R[y]),
+        (0, 5) => Dec(R[y]),
+        (0, 6) => Ld(R[y], Operand::Immediate(next())),
+        (0, _) => ACCUMULATOR[y],
+        (1, 6) if y == 6 => Halt,
+        (1, _) => Ld(R[y], R[z as usize]),
+        (2, _) => Alu(ALU[y], R[z as usize]),
+        (3, 0) => {
+            match y {
+                0...3 => Ret(Some(CC[y])),
+                4 => StoreA(Address::High(next())),
+                5 => AddSp(next() as i8),
+                6 => LoadA(Address::High(next())),
+                _ => LdHlSp(next() as i8),
+            }
+        }
+        (3, 1) if q == 0 => Pop(RP2[p]),
+        (3, 1) => [Ret(None), Reti, JpHl, LdSpHl][p],
+        (3, 2) => {
+            match y {
+                0...3 => Jp(Some(CC[y]), word(&mut next)),
+                4 => StoreA(Address::HighC),
+                5 => StoreA(Address::Absolute(word(&mut next))),
+                6 => LoadA(Address::HighC),
+                _ => LoadA(Address::Absolute(word(&mut next))),
+            }
+        }
+        (3, 3) => {
+            match y {
+                0 => Jp(None, word(&mut next)),
+                1 => decode_prefixed(next()),
+                6 => Di,
+                7 => Ei,
+                _ => Illegal(opcode),
+            }
+        }
+        (3, 4) if y < 4 => Call(Some(CC[y]), word(&mut next)),
+        (3, 5) if q == 0 => Push(RP2[p]),
+        (3, 5) if p == 0 => Call(None, word(&mut next)),
+        (3, 6) => Alu(ALU[y], Operand::Immediate(next())),
+        (3, 7) => Rst(y as u16 * 8),
+        _ => Illegal(opcode),
+    }
+}
+
+fn decode_prefixed(opcode: u8) -> Instruction {
+    let y = (opcode >> 3) & 0b111;
+    let operand = R[(opcode & 0b111) as usize];
+
+    match opcode >> 6 {
+        0 => Shift(ROT[y as usize], operand),
+        1 => Bit(y, operand),
+        2 => Res(y, operand),
+        _ => Set(y, operand),
+    }
+}
+
+// immediate words are little-endian
+fn word<F: FnMut() -> u8>(next: &mut F) -> u16 {
+    let low = next() as u16;
+    let high = next() as u16;
+    high << 8 | low
+}
+
+impl Operand {
+    // clocks spent reaching the operand, on top of the instruction itself
+    fn cycles(&self) -> u8 {
+        match *self {
+            Operand::Reg(_) => 0,
+            Operand::IndirectHL | Operand::Immediate(_) => 4,
+        }
+    }
+}
+
+impl Instruction {
+    /// Size in bytes, including the 0xCB prefix and any immediates
+    pub fn length(&self) -> u16 {
+        match *self {
+            Ld(_, Operand::Immediate(_)) |
+            Alu(_, Operand::Immediate(_)) |
+            LoadA(Address::High(_)) |
+            StoreA(Address::High(_)) |
+            LdHlSp(_) |
+            AddSp(_) |
+            Jr(..) |
+            Stop |
+            Shift(..) |
+            Bit(..) |
+            Res(..) |
+            Set(..) => 2,
+            LoadA(Address::Absolute(_)) |
+            StoreA(Address::Absolute(_)) |
+            Ld16(..) |
+            StoreSp(_) |
+            Jp(..) |
+            Call(..) => 3,
+            _ => 1,
+        }
+    }
+
+    /// Clocks taken, where `taken` says whether a conditional branch was followed
+    pub fn cycles(&self, taken: bool) -> u8 {
+        match *self {
+            Ld(to, from) => 4 + to.cycles() + from.cycles(),
+            LoadA(address) | StoreA(address) => {
+                match address {
+                    Address::High(_) => 12,
+                    Address::Absolute(_) => 16,
+                    _ => 8,
+                }
+            }
+            Ld16(..) | LdHlSp(_) => 12,
+            StoreSp(_) => 20,
+            LdSpHl | Inc16(_) | Dec16(_) | AddHl(_) => 8,
+            Inc(Operand::IndirectHL) | Dec(Operand::IndirectHL) => 12,
+            AddSp(_) => 16,
+            Alu(_, from) => 4 + from.cycles(),
+            Jr(None, _) => 12,
+            Jr(Some(_), _) => if taken { 12 } else { 8 },
+            Jp(None, _) => 16,
+            Jp(Some(_), _) => if taken { 16 } else { 12 },
+            Call(None, _) => 24,
+            Call(Some(_), _) => if taken { 24 } else { 12 },
+            Ret(None) | Reti | Rst(_) | Push(_) => 16,
+            Ret(Some(_)) => if taken { 20 } else { 8 },
+            Pop(_) => 12,
+            // BIT only reads (HL), the other prefixed operations also write it back
+            Bit(_, Operand::IndirectHL) => 12,
+            Shift(_, Operand::IndirectHL) |
+            Res(_, Operand::IndirectHL) |
+            Set(_, Operand::IndirectHL) => 16,
+            Shift(..) | Bit(..) | Res(..) | Set(..) => 8,
+            _ => 4,
+        }
+    }
+}
+
+impl fmt::Display for Reg8 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Reg16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand::Reg(reg) => write!(f, "{}", reg),
+            Operand::IndirectHL => write!(f, "(HL)"),
+            Operand::Immediate(value) => write!(f, "${:0>2X}", value),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Address::BC => write!(f, "(BC)"),
+            Address::DE => write!(f, "(DE)"),
+            Address::HLIncrement => write!(f, "(HL+)"),
+            Address::HLDecrement => write!(f, "(HL-)"),
+            Address::High(offset) => write!(f, "($FF{:0>2X})", offset),
+            Address::HighC => write!(f, "($FF00+C)"),
+            Address::Absolute(address) => write!(f, "(${:0>4X})", address),
+        }
+    }
+}
+
+impl Alu {
+    pub fn mnemonic(&self) -> &'static str {
+        match *self {
+            Alu::Add => "ADD",
+            Alu::Adc => "ADC",
+            Alu::Sub => "SUB",
+            Alu::Sbc => "SBC",
+            Alu::And => "AND",
+            Alu::Xor => "XOR",
+            Alu::Or => "OR",
+            Alu::Cp => "CP",
+        }
+    }
+}
+
+impl Shift {
+    pub fn mnemonic(&self) -> &'static str {
+        match *self {
+            Shift::Rlc => "RLC",
+            Shift::Rrc => "RRC",
+            Shift::Rl => "RL",
+            Shift::Rr => "RR",
+            Shift::Sla => "SLA",
+            Shift::Sra => "SRA",
+            Shift::Swap => "SWAP",
+            Shift::Srl => "SRL",
+        }
+    }
+}
+
+// the condition of a branch, if it has one, comes before its destination
+fn branch(condition: Option<Condition>, destination: String) -> Vec<String> {
+    match condition {
+        Some(condition) => vec![condition.to_string(), destination],
+        None => vec![destination],
+    }
+}
+
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        match *self {
+            Nop => "NOP",
+            Stop => "STOP",
+            Halt => "HALT",
+            Di => "DI",
+            Ei => "EI",
+            LoadA(Address::High(_)) | StoreA(Address::High(_)) => "LDH",
+            Ld(..) | LoadA(_) | StoreA(_) | Ld16(..) | StoreSp(_) | LdSpHl | LdHlSp(_) => "LD",
+            Inc(_) | Inc16(_) => "INC",
+            Dec(_) | Dec16(_) => "DEC",
+            AddHl(_) | AddSp(_) => "ADD",
+            Alu(operation, _) => operation.mnemonic(),
+            Rlca => "RLCA",
+            Rrca => "RRCA",
+            Rla => "RLA",
+            Rra => "RRA",
+            Daa => "DAA",
+            Cpl => "CPL",
+            Scf => "SCF",
+            Ccf => "CCF",
+            Jr(..) => "JR",
+            Jp(..) | JpHl => "JP",
+            Call(..) => "CALL",
+            Ret(_) => "RET",
+            Reti => "RETI",
+            Rst(_) => "RST",
+            Push(_) => "PUSH",
+            Pop(_) => "POP",
+            Shift(operation, _) => operation.mnemonic(),
+            Bit(..) => "BIT",
+            Res(..) => "RES",
+            Set(..) => "SET",
+            Illegal(_) => "DB",
+        }
+    }
+
+    pub fn operands(&self) -> Vec<String> {
+        let a = "A".to_string();
+        match *self {
+            Ld(to, from) => vec![to.to_string(), from.to_string()],
+            LoadA(address) => vec![a, address.to_string()],
+            StoreA(address) => vec![address.to_string(), a],
+            Ld16(to, value) => vec![to.to_string(), format!("${:0>4X}", value)],
+            StoreSp(address) => vec![format!("(${:0>4X})", address), "SP".to_string()],
+            LdSpHl => vec!["SP".to_string(), "HL".to_string()],
+            LdHlSp(offset) => vec!["HL".to_string(), format!("SP{:+}", offset)],
+            Inc(operand) | Dec(operand) => vec![operand.to_string()],
+            Inc16(reg) | Dec16(reg) => vec![reg.to_string()],
+            AddHl(from) => vec!["HL".to_string(), from.to_string()],
+            AddSp(offset) => vec!["SP".to_string(), format!("{:+}", offset)],
+            Alu(Alu::Add, from) | Alu(Alu::Adc, from) | Alu(Alu::Sbc, from) => {
+                vec![a, from.to_string()]
+            }
+            Alu(_, from) => vec![from.to_string()],
+            Jr(condition, offset) => branch(condition, format!("{:+}", offset)),
+            Jp(condition, address) | Call(condition, address) => {
+                branch(condition, format!("${:0>4X}", address))
+            }
+            JpHl => vec!["HL".to_string()],
+            Ret(Some(condition)) => vec![condition.to_string()],
+            Rst(vector) => vec![format!("${:0>2X}", vector)],
+            Push(reg) | Pop(reg) => vec![reg.to_string()],
+            Shift(_, operand) => vec![operand.to_string()],
+            Bit(bit, operand) | Res(bit, operand) | Set(bit, operand) => {
+                vec![bit.to_string(), operand.to_string()]
+            }
+            Illegal(opcode) => vec![format!("${:0>2X}", opcode)],
+            _ => vec![],
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operands = self.operands();
+        if operands.is_empty() {
+            write!(f, "{}", self.mnemonic())
+        } else {
+            write!(f, "{} {}", self.mnemonic(), operands.join(", "))
+        }
+    }
+}
+
+#[test]
+fn lengths_match_bytes_decoded() {
+    for opcode in 0..0x100 {
+        for &prefixed in &[0x00u8, 0x46] {
+            let bytes = [opcode as u8, prefixed, 0x12, 0x34];
+            let mut read = 0;
+            let instruction = decode(|| {
+                read += 1;
+                bytes[read - 1]
+            });
+            assert_eq!(instruction.length(), read as u16, "{:0>2X}: {}", opcode, instruction);
+        }
+    }
+}
+
+#[test]
+fn eleven_illegal_opcodes() {
+    let illegal: Vec<u8> = (0..0x100)
+        .map(|opcode| opcode as u8)
+        .filter(|&opcode| decode(|| opcode) == Illegal(opcode))
+        .collect();
+    assert_eq!(illegal,
+               vec![0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD]);
+}
@@ -98,17 +98,12 @@ impl LcdScreen {
             (8, 16)
         }
     }
-
-    pub fn step(&mut self) {
-        println!("screen step!");
-        self.memory.write().unwrap()[0xFF40 as u16] |= 0b10000000;
-    }
 }
 
 impl window::Drawable for LcdScreen {
     fn update(&mut self) {
         if self.window.is_open() {
-            self.control = { self.memory.read().unwrap()[0xFF40 as u16] };
+            self.control = { self.memory.read().unwrap().peek(0xFF40) };
             self.window.get_scroll_wheel().map(|scroll| {
                 let amount = self.width.wrapping_mul(scroll.1 as usize);
                 self.scroll = self.scroll.wrapping_add(amount as u16);
@@ -124,7 +119,9 @@ impl window::Drawable for LcdScreen {
 
     fn draw(&mut self) {
         let offset = self.scroll.wrapping_sub(self.offset);
-        let byte = { self.memory.read().unwrap()[offset] };
+        // the whole address space at once, without the ppu or DMA getting in the way
+        let bytes = { self.memory.read().unwrap().read_range(0x0000..=0xFFFF) };
+        let byte = bytes[offset as usize];
         let s = format!("0x{:0>4X}: {:0>4X}: {:0>2X}",
                         self.scroll,
                         offset,
@@ -132,10 +129,8 @@ impl window::Drawable for LcdScreen {
         self.window.set_title(&s);
 
         let mut count = self.scroll;
-        let memory = { self.memory.read().unwrap() };
-
         for i in &mut self.buffer {
-            let gray = memory[count] as u32;
+            let gray = bytes[count as usize] as u32;
             *i = gray << 16 | gray << 8 | gray;
             count = count.wrapping_sub(1);
         }
@@ -3,36 +3,104 @@
 #![cfg_attr(feature="clippy", plugin(clippy))]
 
 extern crate crc;
+#[cfg(test)]
+extern crate serde_json;
 
 mod gameboy;
 mod cpu;
+mod crash;
+mod instruction;
+mod disasm;
+mod testrom;
 mod memory;
 mod cart;
+mod mbc;
 mod header;
 mod debug;
 mod lcd;
+mod ppu;
 mod window;
+#[cfg(test)]
+mod single_step;
 
 use std::env;
 use std::fs;
 use std::io::Read;
 use std::path::Path;
+use std::process;
 use crc::crc32;
 
 fn main() {
-    let boot = load_bootrom(Path::new("dmg_rom.bin"));
+    if env::args().nth(1) == Some("disasm".to_string()) {
+        let rom_path = env::args().nth(2).expect("usage: rustboy disasm rom.gb");
+        print!("{}", disasm::disassemble_rom(&load_rom(Path::new(&rom_path))));
+        return;
+    }
+
+    // exits with 0 when the rom passes, 1 when it fails and 2 when it runs out of time
+    if env::args().nth(1) == Some("blargg".to_string()) {
+        let rom_path = env::args().nth(2).expect("usage: rustboy blargg rom.gb");
+        let (verdict, output) = testrom::blargg(load_cart(Path::new(&rom_path)), testrom::BLARGG_BUDGET);
+        println!("{}\n{:?}", output, verdict);
+        process::exit(verdict.exit_code());
+    }
+
+    if env::args().nth(1) == Some("mooneye".to_string()) {
+        let rom_path = env::args().nth(2).expect("usage: rustboy mooneye rom.gb");
+        let (verdict, registers) = testrom::mooneye(load_cart(Path::new(&rom_path)), testrom::MOONEYE_BUDGET);
+        println!("{:?}\n{:?}", registers, verdict);
+        process::exit(verdict.exit_code());
+    }
+
+    let model = env::var("MODEL")
+        .map(|name| gameboy::Model::from_name(&name).expect("MODEL should be dmg, mgb, sgb or cgb"))
+        .unwrap_or(gameboy::Model::Dmg);
+
+    let illegal = env::var("ILLEGAL")
+        .map(|name| cpu::IllegalPolicy::from_name(&name).expect("ILLEGAL should be lock, break or abort"))
+        .unwrap_or(cpu::IllegalPolicy::Lock);
+
+    // with OPEN_VRAM set, the cpu can reach vram and oam whatever the ppu is doing
+    let ppu_blocking = env::var("OPEN_VRAM").is_err();
+
+    // dmg_rom.bin only boots a dmg, every other model starts as if it had already booted
+    let boot = if model == gameboy::Model::Dmg && env::var("SKIP_BOOT").is_err() {
+        load_bootrom(Path::new("dmg_rom.bin"))
+    } else {
+        None
+    };
 
     let cart_path = env::args().nth(1).unwrap_or("roms/test.gb".to_string());
 
-    let cart = load_cart(Path::new(&cart_path));
+    let mut cart = load_cart(Path::new(&cart_path));
+    if let Ok(name) = env::var("RTC") {
+        cart.set_clock(mbc::Clock::from_name(&name).expect("RTC should be host or emulated"));
+    }
+    if cart.has_battery() {
+        let save = Path::new(&cart_path).with_extension("sav");
+        cart.attach_save(&save).expect("could not read the save file");
+    }
     println!("{}", cart);
 
-    gameboy::run(boot, cart);
+    // there is no rumble motor to drive, so events are dropped
+    if let Some(report) = gameboy::run(boot, cart, model, illegal, ppu_blocking, |_| {}) {
+        println!("{}", report);
+        if let Ok(path) = env::var("CRASH_REPORT") {
+            report.save(Path::new(&path)).expect("could not write the crash report");
+            println!("crash report written to {}", path);
+        }
+        process::exit(1);
+    }
 }
 
-fn load_bootrom(path: &Path) -> [u8; gameboy::BOOTROM_SIZE] {
-    let mut file = fs::File::open(path)
-        .expect("Please download dmg_rom.bin");
+fn load_bootrom(path: &Path) -> Option<[u8; gameboy::BOOTROM_SIZE]> {
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => {
+            println!("{:?} not found, skipping the boot rom", path);
+            return None;
+        }
+    };
 
     let mut buffer = [0; gameboy::BOOTROM_SIZE];
     file.read_exact(&mut buffer).unwrap();
@@ -41,7 +109,7 @@ fn load_bootrom(path: &Path) -> [u8; gameboy::BOOTROM_SIZE] {
     assert_eq!(crc32::checksum_ieee(&buffer), dmg_rom_crc32,
         "{:?} has invalid crc32, expected {:x}", path, dmg_rom_crc32);
 
-    buffer
+    Some(buffer)
 }
 
 fn load_rom(path: &Path) -> Vec<u8> {
@@ -66,7 +134,7 @@ fn checksums() {
                 // We only test against official cartridges, not homebrew
                 if entry.file_name().to_string_lossy().contains("(") {
                     println!("testing {:?}", entry.file_name());
-                    assert!(load_cart(entry.path()).is_valid());
+                    assert!(load_cart(&entry.path()).is_valid());
                 }
             }
         }
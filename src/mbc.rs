@@ -0,0 +1,646 @@
+// Memory bank controllers switch parts of a large rom and ram into the cart's small address windows.
+// Writes to the rom area don't change the rom, they set the controller's registers instead
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use gameboy;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+/// Where a real time clock gets the time from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clock {
+    // wall clock time, like a cart in a real game boy
+    Host,
+    // the clocks the emulator has run, so every run sees the same time
+    Emulated,
+}
+
+impl Clock {
+    pub fn from_name(name: &str) -> Option<Clock> {
+        match name.to_lowercase().as_str() {
+            "host" => Some(Clock::Host),
+            "emulated" => Some(Clock::Emulated),
+            _ => None,
+        }
+    }
+}
+
+/// The controller on the cart, picked by the cart type byte at 0x147
+#[derive(Debug)]
+pub enum Mbc {
+    None,
+    Mbc1(Mbc1),
+    Mbc2(Mbc2),
+    Mbc3(Mbc3),
+    Mbc5(Mbc5),
+}
+
+impl Mbc {
+    pub fn new(cart_type: u8, rom: &[u8]) -> Mbc {
+        match cart_type {
+            0x01...0x03 => Mbc::Mbc1(Mbc1::new(rom)),
+            0x05...0x06 => Mbc::Mbc2(Mbc2::new()),
+            0x0F...0x13 => Mbc::Mbc3(Mbc3::new(cart_type, rom)),
+            0x19...0x1E => Mbc::Mbc5(Mbc5::new(cart_type)),
+            _ => Mbc::None,
+        }
+    }
+
+    pub fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let bank = match *self {
+            Mbc::None => address as usize / ROM_BANK_SIZE,
+            Mbc::Mbc1(ref mbc) => mbc.rom_bank_at(address),
+            Mbc::Mbc2(ref mbc) => if address < 0x4000 { 0 } else { mbc.rom_bank as usize },
+            Mbc::Mbc3(ref mbc) => if address < 0x4000 { 0 } else { mbc.rom_bank as usize },
+            Mbc::Mbc5(ref mbc) => if address < 0x4000 { 0 } else { mbc.rom_bank as usize },
+        };
+        read_bank(rom, bank, ROM_BANK_SIZE, address as usize % ROM_BANK_SIZE)
+    }
+
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        match *self {
+            Mbc::None => {}
+            Mbc::Mbc1(ref mut mbc) => mbc.write(address, value),
+            Mbc::Mbc2(ref mut mbc) => mbc.write(address, value),
+            Mbc::Mbc3(ref mut mbc) => mbc.write(address, value),
+            Mbc::Mbc5(ref mut mbc) => mbc.write(address, value),
+        }
+    }
+
+    pub fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        let offset = address as usize - 0xA000;
+        match *self {
+            Mbc::None => read_bank(ram, 0, RAM_BANK_SIZE, offset),
+            Mbc::Mbc1(ref mbc) if mbc.ram_enabled => read_bank(ram, mbc.ram_bank(), RAM_BANK_SIZE, offset),
+            Mbc::Mbc2(ref mbc) if mbc.ram_enabled => read_bank(ram, 0, MBC2_RAM_SIZE, offset) | 0xF0,
+            Mbc::Mbc3(ref mbc) if mbc.ram_enabled => {
+                match (mbc.ram_bank, mbc.rtc.as_ref()) {
+                    (0x00...0x07, _) => read_bank(ram, mbc.ram_bank as usize, RAM_BANK_SIZE, offset),
+                    (0x08...0x0C, Some(rtc)) => rtc.read(mbc.ram_bank),
+                    _ => 0xFF,
+                }
+            }
+            Mbc::Mbc5(ref mbc) if mbc.ram_enabled => read_bank(ram, mbc.ram_bank as usize, RAM_BANK_SIZE, offset),
+            _ => 0xFF,
+        }
+    }
+
+    /// Returns false if the write went nowhere, because ram is disabled or missing
+    pub fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) -> bool {
+        let offset = address as usize - 0xA000;
+        match *self {
+            Mbc::None => write_bank(ram, 0, RAM_BANK_SIZE, offset, value),
+            Mbc::Mbc1(ref mbc) if mbc.ram_enabled => write_bank(ram, mbc.ram_bank(), RAM_BANK_SIZE, offset, value),
+            Mbc::Mbc2(ref mbc) if mbc.ram_enabled => write_bank(ram, 0, MBC2_RAM_SIZE, offset, value & 0x0F),
+            Mbc::Mbc3(ref mut mbc) if mbc.ram_enabled => {
+                match (mbc.ram_bank, mbc.rtc.as_mut()) {
+                    (0x00...0x07, _) => write_bank(ram, mbc.ram_bank as usize, RAM_BANK_SIZE, offset, value),
+                    (0x08...0x0C, Some(rtc)) => {
+                        rtc.write(mbc.ram_bank, value);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+            Mbc::Mbc5(ref mbc) if mbc.ram_enabled => {
+                write_bank(ram, mbc.ram_bank as usize, RAM_BANK_SIZE, offset, value)
+            }
+            _ => false,
+        }
+    }
+
+    /// The rom bank switched in at 0x4000
+    pub fn rom_bank(&self) -> usize {
+        match *self {
+            Mbc::None => 1,
+            Mbc::Mbc1(ref mbc) => mbc.rom_bank_at(0x4000),
+            Mbc::Mbc2(ref mbc) => mbc.rom_bank as usize,
+            Mbc::Mbc3(ref mbc) => mbc.rom_bank as usize,
+            Mbc::Mbc5(ref mbc) => mbc.rom_bank as usize,
+        }
+    }
+
+    /// Whether a rumble cart has its motor on
+    pub fn rumble(&self) -> bool {
+        match *self {
+            Mbc::Mbc5(ref mbc) => mbc.motor,
+            _ => false,
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: Clock) {
+        if let Mbc::Mbc3(Mbc3 { rtc: Some(ref mut rtc), .. }) = *self {
+            rtc.clock = clock;
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        if let Mbc::Mbc3(Mbc3 { rtc: Some(ref mut rtc), .. }) = *self {
+            rtc.tick(cycles);
+        }
+    }
+
+    /// The clock as saved after the ram, or nothing without a clock
+    pub fn save_rtc(&mut self) -> Vec<u8> {
+        match *self {
+            Mbc::Mbc3(Mbc3 { rtc: Some(ref mut rtc), .. }) => rtc.footer(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn load_rtc(&mut self, footer: &[u8]) {
+        if let Mbc::Mbc3(Mbc3 { rtc: Some(ref mut rtc), .. }) = *self {
+            rtc.load_footer(footer);
+        }
+    }
+
+    /// Bytes of ram on the controller itself, which the ram size header doesn't count
+    pub fn builtin_ram(&self) -> usize {
+        match *self {
+            Mbc::Mbc2(_) => MBC2_RAM_SIZE,
+            _ => 0,
+        }
+    }
+}
+
+// Bank numbers wrap around the banks that exist, like the unconnected upper address lines.
+// With nothing there at all, the bus floats high
+fn read_bank(memory: &[u8], bank: usize, bank_size: usize, offset: usize) -> u8 {
+    if memory.is_empty() {
+        return 0xFF;
+    }
+    memory[(bank * bank_size + offset) % memory.len()]
+}
+
+fn write_bank(memory: &mut [u8], bank: usize, bank_size: usize, offset: usize, value: u8) -> bool {
+    if memory.is_empty() {
+        return false;
+    }
+    let length = memory.len();
+    memory[(bank * bank_size + offset) % length] = value;
+    true
+}
+
+/**
+ * MBC1 has a 5 bit rom bank register, and a 2 bit register that either extends it, or picks the ram bank
+ *
+ *   0000-1FFF  ram enable, when the low nibble is A
+ *   2000-3FFF  BANK1, the low 5 bits of the rom bank. 0 selects 1, even when BANK2 is set
+ *   4000-5FFF  BANK2, rom bank bits 5-6, or the ram bank in mode 1
+ *   6000-7FFF  mode. In mode 1, BANK2 also applies to 0000-3FFF and to ram
+ *
+ * MBC1M multicarts wire BANK1 as 4 bits instead, so BANK2 picks one of four 256KiB games
+ **/
+#[derive(Debug)]
+pub struct Mbc1 {
+    ram_enabled: bool,
+    bank1: u8,
+    bank2: u8,
+    mode: u8,
+    multicart: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom: &[u8]) -> Mbc1 {
+        Mbc1 {
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            mode: 0,
+            multicart: is_multicart(rom),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000...0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000...0x3FFF => self.bank1 = if value & 0x1F == 0 { 1 } else { value & 0x1F },
+            0x4000...0x5FFF => self.bank2 = value & 0x03,
+            _ => self.mode = value & 0x01,
+        }
+    }
+
+    fn rom_bank_at(&self, address: u16) -> usize {
+        let (bank1, shift) = if self.multicart {
+            (self.bank1 & 0x0F, 4)
+        } else {
+            (self.bank1, 5)
+        };
+        let high = (self.bank2 as usize) << shift;
+
+        match address {
+            0x0000...0x3FFF if self.mode == 1 => high,
+            0x0000...0x3FFF => 0,
+            _ => high | bank1 as usize,
+        }
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.mode == 1 {
+            self.bank2 as usize
+        } else {
+            0
+        }
+    }
+}
+
+/**
+ * MBC2 has 16 rom banks, and 512 half bytes of ram that repeat across A000-BFFF.
+ * The top nibble of the ram isn't there, so it reads as 1s
+ *
+ *   0000-3FFF  with address bit 8 clear, ram enable when the low nibble is A.
+ *              with it set, the rom bank in the low nibble, where 0 selects 1
+ **/
+#[derive(Debug)]
+pub struct Mbc2 {
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+const MBC2_RAM_SIZE: usize = 512;
+
+impl Mbc2 {
+    pub fn new() -> Mbc2 {
+        Mbc2 {
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000...0x3FFF if address & 0x0100 == 0 => self.ram_enabled = value & 0x0F == 0x0A,
+            0x0000...0x3FFF => self.rom_bank = if value & 0x0F == 0 { 1 } else { value & 0x0F },
+            _ => {}
+        }
+    }
+}
+
+/**
+ * MBC3 has 7 bit rom banking, 4 ram banks, and on some carts a real time clock.
+ * MBC30 is the same chip with an 8th rom bank bit and 8 ram banks
+ *
+ *   0000-1FFF  ram and clock enable, when the low nibble is A
+ *   2000-3FFF  rom bank, where 0 selects 1
+ *   4000-5FFF  00-07 picks a ram bank, 08-0C puts a clock register at A000-BFFF instead
+ *   6000-7FFF  writing 00 then 01 latches the clock, so it can be read while it keeps counting
+ **/
+#[derive(Debug)]
+pub struct Mbc3 {
+    ram_enabled: bool,
+    rom_bank: u8,
+    ram_bank: u8,
+    mbc30: bool,
+    rtc: Option<Rtc>,
+    last_latch: u8,
+}
+
+impl Mbc3 {
+    pub fn new(cart_type: u8, rom: &[u8]) -> Mbc3 {
+        let ram_size = rom.get(0x149).cloned().unwrap_or(0);
+        Mbc3 {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            // nothing else needs more than 2MiB of rom or 32KiB of ram
+            mbc30: rom.len() > ROM_BANK_SIZE * 128 || ram_size == 0x05,
+            rtc: if cart_type == 0x0F || cart_type == 0x10 { Some(Rtc::new()) } else { None },
+            last_latch: 0xFF,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000...0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000...0x3FFF => {
+                let bank = if self.mbc30 { value } else { value & 0x7F };
+                self.rom_bank = if bank == 0 { 1 } else { bank };
+            }
+            0x4000...0x5FFF => {
+                self.ram_bank = match value {
+                    0x00...0x07 if self.mbc30 => value,
+                    0x00...0x07 => value & 0x03,
+                    _ => value,
+                }
+            }
+            _ => {
+                if self.last_latch == 0x00 && value == 0x01 {
+                    if let Some(ref mut rtc) = self.rtc {
+                        rtc.latch();
+                    }
+                }
+                self.last_latch = value;
+            }
+        }
+    }
+}
+
+/**
+ * MBC5 has a 9 bit rom bank for up to 8MiB, where bank 0 can be switched in too, and 16 ram banks
+ *
+ *   0000-1FFF  ram enable, when the low nibble is A
+ *   2000-2FFF  low 8 bits of the rom bank
+ *   3000-3FFF  bit 8 of the rom bank
+ *   4000-5FFF  ram bank. Rumble carts use bit 3 for the motor instead, leaving 8 ram banks
+ **/
+#[derive(Debug)]
+pub struct Mbc5 {
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    has_rumble: bool,
+    motor: bool,
+}
+
+impl Mbc5 {
+    pub fn new(cart_type: u8) -> Mbc5 {
+        Mbc5 {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble: cart_type >= 0x1C,
+            motor: false,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000...0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000...0x2FFF => self.rom_bank = self.rom_bank & 0x100 | value as u16,
+            0x3000...0x3FFF => self.rom_bank = self.rom_bank & 0xFF | (value as u16 & 0x01) << 8,
+            0x4000...0x5FFF if self.has_rumble => {
+                self.ram_bank = value & 0x07;
+                self.motor = value & 0x08 != 0;
+            }
+            0x4000...0x5FFF => self.ram_bank = value & 0x0F,
+            _ => {}
+        }
+    }
+}
+
+/**
+ * The clock registers, selected by writing their number to 4000-5FFF
+ *
+ *   08  seconds 0-59
+ *   09  minutes 0-59
+ *   0A  hours 0-23
+ *   0B  low 8 bits of the day counter
+ *   0C  bit 0 is bit 8 of the day counter, bit 6 halts the clock,
+ *       bit 7 is set when the day counter overflows, until it is cleared
+ **/
+#[derive(Debug)]
+pub struct Rtc {
+    pub clock: Clock,
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub days: u16,
+    pub halted: bool,
+    pub carry: bool,
+    // what reads see, copied from the counters on a latch
+    pub latched: [u8; 5],
+    // clocks towards the next emulated second
+    cycles: u64,
+    // when host time was last counted
+    updated: SystemTime,
+}
+
+impl Rtc {
+    pub fn new() -> Rtc {
+        Rtc {
+            clock: Clock::Host,
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halted: false,
+            carry: false,
+            latched: [0; 5],
+            cycles: 0,
+            updated: SystemTime::now(),
+        }
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        if self.clock == Clock::Emulated && !self.halted {
+            self.cycles += cycles as u64;
+            if self.cycles >= gameboy::CLOCK {
+                self.cycles -= gameboy::CLOCK;
+                self.advance(1);
+            }
+        }
+    }
+
+    // Count the host time that went by since the last look
+    fn sync(&mut self) {
+        let now = SystemTime::now();
+        if self.clock == Clock::Host {
+            if let Ok(elapsed) = now.duration_since(self.updated) {
+                if !self.halted {
+                    self.advance(elapsed.as_secs());
+                }
+                // keep the part of a second that hasn't been counted yet
+                self.updated = now - Duration::new(0, elapsed.subsec_nanos());
+                return;
+            }
+        }
+        self.updated = now;
+    }
+
+    // Out of range values are normalized rather than counting up to 63 like the hardware
+    pub fn advance(&mut self, seconds: u64) {
+        let total = seconds + self.seconds as u64 + self.minutes as u64 * 60 + self.hours as u64 * 3600 +
+                    self.days as u64 * 86400;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        let days = total / 86400;
+        if days > 0x1FF {
+            self.carry = true;
+        }
+        self.days = (days % 0x200) as u16;
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        let high = (self.days >> 8) as u8 | (self.halted as u8) << 6 | (self.carry as u8) << 7;
+        [self.seconds, self.minutes, self.hours, self.days as u8, high]
+    }
+
+    fn latch(&mut self) {
+        self.sync();
+        self.latched = self.registers();
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        self.latched[register as usize - 0x08]
+    }
+
+    /**
+     * The footer other emulators put after the ram: the five registers, the five latched registers,
+     * each as a little endian u32, then the unix time they were saved at as a u64.
+     * Older saves use a u32 for the time, making the footer 44 bytes instead of 48
+     **/
+    fn footer(&mut self) -> Vec<u8> {
+        self.sync();
+        let mut footer = Vec::with_capacity(48);
+        for &register in self.registers().iter().chain(self.latched.iter()) {
+            footer.extend_from_slice(&[register, 0, 0, 0]);
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        for byte in 0..8 {
+            footer.push((now >> (byte * 8)) as u8);
+        }
+        footer
+    }
+
+    fn load_footer(&mut self, footer: &[u8]) {
+        if footer.len() != 44 && footer.len() != 48 {
+            return;
+        }
+
+        let word = |index: usize| footer[index * 4];
+        let registers = [word(0), word(1), word(2), word(3), word(4)];
+        for (index, &value) in registers.iter().enumerate() {
+            self.write(0x08 + index as u8, value);
+        }
+        for index in 0..5 {
+            self.latched[index] = word(5 + index);
+        }
+
+        // on the host clock, count the time the game boy was switched off
+        let saved = footer[40..].iter().rev().fold(0, |time, &byte| time << 8 | byte as u64);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        if self.clock == Clock::Host && !self.halted && now > saved {
+            self.advance(now - saved);
+        }
+        self.updated = SystemTime::now();
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        self.sync();
+        match register {
+            0x08 => {
+                self.seconds = value & 0x3F;
+                // writing the seconds restarts the current second
+                self.cycles = 0;
+            }
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = self.days & 0x100 | value as u16,
+            _ => {
+                self.days = self.days & 0xFF | (value as u16 & 0x01) << 8;
+                self.halted = value & 0x40 != 0;
+                self.carry = value & 0x80 != 0;
+            }
+        }
+    }
+}
+
+// Every cart header has this at 0x0104, or the boot rom refuses to start it
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+    0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+    0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+// A 1MiB MBC1 cart with the logo at the start of a later 256KiB game is a multicart
+fn is_multicart(rom: &[u8]) -> bool {
+    const GAME_SIZE: usize = 0x40000;
+
+    rom.len() == GAME_SIZE * 4 &&
+    (1..4).any(|game| rom[game * GAME_SIZE + 0x0104..game * GAME_SIZE + 0x0134] == NINTENDO_LOGO[..])
+}
+
+#[test]
+fn mbc1_bank_zero_selects_bank_one() {
+    let mut rom = vec![0; ROM_BANK_SIZE * 64];
+    for bank in 0..64 {
+        rom[bank * ROM_BANK_SIZE] = bank as u8;
+    }
+    let mut mbc = Mbc::new(0x01, &rom);
+
+    assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+    mbc.write_rom(0x2000, 0x00);
+    assert_eq!(mbc.read_rom(&rom, 0x4000), 1);
+
+    // BANK2 extends the bank number, and bank 0x20 still remaps to 0x21
+    mbc.write_rom(0x4000, 0x01);
+    assert_eq!(mbc.read_rom(&rom, 0x4000), 0x21);
+    assert_eq!(mbc.read_rom(&rom, 0x0000), 0);
+
+    // in mode 1 it also moves bank 0
+    mbc.write_rom(0x6000, 0x01);
+    assert_eq!(mbc.read_rom(&rom, 0x0000), 0x20);
+}
+
+#[test]
+fn mbc2_ram_is_four_bits_and_repeats() {
+    let rom = vec![0; ROM_BANK_SIZE * 16];
+    let mut ram = vec![0; MBC2_RAM_SIZE];
+    let mut mbc = Mbc::new(0x05, &rom);
+
+    mbc.write_ram(&mut ram, 0xA000, 0x5A);
+    assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFF);
+
+    // bit 8 picks the register, so this enables ram rather than switching banks
+    mbc.write_rom(0x0000, 0x0A);
+    mbc.write_ram(&mut ram, 0xA000, 0x5A);
+    assert_eq!(mbc.read_ram(&ram, 0xA000), 0xFA);
+    assert_eq!(mbc.read_ram(&ram, 0xA200), 0xFA);
+    assert_eq!(mbc.rom_bank(), 1);
+
+    mbc.write_rom(0x0100, 0x03);
+    assert_eq!(mbc.rom_bank(), 3);
+}
+
+#[test]
+fn mbc3_latches_the_emulated_clock() {
+    let rom = vec![0; ROM_BANK_SIZE * 4];
+    let mut ram = vec![0; RAM_BANK_SIZE];
+    let mut mbc = Mbc::new(0x10, &rom);
+    mbc.set_clock(Clock::Emulated);
+    mbc.write_rom(0x0000, 0x0A);
+
+    for _ in 0..(gameboy::CLOCK * 61 / 128) {
+        mbc.tick(128);
+    }
+    mbc.write_rom(0x4000, 0x08);
+    assert_eq!(mbc.read_ram(&ram, 0xA000), 0);
+
+    mbc.write_rom(0x6000, 0x00);
+    mbc.write_rom(0x6000, 0x01);
+    assert_eq!(mbc.read_ram(&ram, 0xA000), 1);
+    mbc.write_rom(0x4000, 0x09);
+    assert_eq!(mbc.read_ram(&ram, 0xA000), 1);
+
+    // halting the clock stops it counting
+    mbc.write_rom(0x4000, 0x0C);
+    mbc.write_ram(&mut ram, 0xA000, 0x40);
+    for _ in 0..(gameboy::CLOCK / 128) {
+        mbc.tick(128);
+    }
+    mbc.write_rom(0x6000, 0x00);
+    mbc.write_rom(0x6000, 0x01);
+    mbc.write_rom(0x4000, 0x08);
+    assert_eq!(mbc.read_ram(&ram, 0xA000), 1);
+}
+
+#[test]
+fn mbc5_selects_bank_zero_and_drives_the_motor() {
+    let mut rom = vec![0; ROM_BANK_SIZE * 512];
+    rom[0x1FF * ROM_BANK_SIZE] = 0x42;
+    let mut mbc = Mbc::new(0x1C, &rom);
+
+    mbc.write_rom(0x2000, 0x00);
+    assert_eq!(mbc.rom_bank(), 0);
+    mbc.write_rom(0x2000, 0xFF);
+    mbc.write_rom(0x3000, 0x01);
+    assert_eq!(mbc.read_rom(&rom, 0x4000), 0x42);
+
+    mbc.write_rom(0x4000, 0x08);
+    assert!(mbc.rumble());
+}
@@ -1,141 +1,656 @@
 use gameboy;
 use cart;
-const WRAM_SIZE: usize = 0xDFFF - 0xC000 + 1;
-const VRAM_SIZE: usize = 0x9FFF - 0x8000 + 1;
-const XRAM_SIZE: usize = 0xBFFF - 0xA000 + 1;
+use mbc;
+use cpu::Interrupt;
+use ppu::Ppu;
+// The color game boy has 8 banks of work ram and 2 of video ram, the others use the first ones
+const WRAM_BANK_SIZE: usize = 0xCFFF - 0xC000 + 1;
+const WRAM_SIZE: usize = WRAM_BANK_SIZE * 8;
+const VRAM_BANK_SIZE: usize = 0x9FFF - 0x8000 + 1;
+const VRAM_SIZE: usize = VRAM_BANK_SIZE * 2;
 const HRAM_SIZE: usize = 0xFFFE - 0xFF80 + 1;
 const IO_SIZE: usize = 0xFF7F - 0xFF01 + 1;
+const OAM_SIZE: usize = 0xFE9F - 0xFE00 + 1;
 
-use std::ops::{Index, IndexMut, Range};
+// OAM DMA copies a byte every machine cycle, starting a machine cycle after the write to FF46
+const DMA_LENGTH: u16 = 160;
+const DMA_CLOCKS_PER_BYTE: u16 = 4;
+const DMA_START_DELAY: u16 = 4;
+
+// HDMA copies 16 bytes at a time, keeping the cpu off the bus for 32 clocks each
+const HDMA_BLOCK: u16 = 16;
+const HDMA_CLOCKS_PER_BLOCK: u16 = 32;
+
+use std::io;
+use std::mem;
+use std::ops::RangeInclusive;
+
+/// Anything the cpu can read and write a byte at a time
+pub trait Bus {
+    fn read(&self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+
+    /// The rom bank switched in at 0x4000
+    fn rom_bank(&self) -> usize {
+        1
+    }
+
+    /// Let `cycles` clocks go by
+    fn tick(&mut self, _cycles: u8) {}
+
+    /// The cpu is about to run the instruction at `pc`
+    fn execute(&mut self, _pc: u16) {}
+
+    /// The cpu is working at `pc`, though it may be halted or dispatching an interrupt
+    fn set_pc(&mut self, _pc: u16) {}
+
+    /// Something else has the bus, so the cpu has to wait
+    fn stalled(&self) -> bool {
+        false
+    }
+
+    /// Whether anything wants to hear about `execute`
+    fn hooked(&self) -> bool {
+        false
+    }
+
+    /// Read without anyone noticing, for crash reports and debug views
+    fn peek(&self, address: u16) -> u8 {
+        self.read(address)
+    }
+}
+
+/// The kinds of memory traffic a hook can watch
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// One access by the cpu, as a hook sees it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Traffic {
+    pub access: Access,
+    pub address: u16,
+    // the byte read or written, or the opcode about to run
+    pub value: u8,
+    // the instruction doing the access
+    pub pc: u16,
+    // clocks since power on
+    pub cycles: u64,
+}
+
+/// Names a hook, to remove it again
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HookId(usize);
+
+struct Hook {
+    id: HookId,
+    access: Access,
+    range: RangeInclusive<u16>,
+    callback: Box<Fn(&Traffic) + Send + Sync>,
+}
+
+// An OAM DMA in progress
+struct Dma {
+    source: u16,
+    // false until the instruction that wrote FF46 is over
+    started: bool,
+    // clocks since then
+    clocks: u16,
+}
+
+impl Dma {
+    fn copied(&self) -> u16 {
+        self.clocks.saturating_sub(DMA_START_DELAY) / DMA_CLOCKS_PER_BYTE
+    }
+
+    // the bus is only taken once the start delay is over
+    fn running(&self) -> bool {
+        self.started && self.clocks >= DMA_START_DELAY
+    }
+}
+
+// A color game boy HDMA waiting for the next hblank
+struct Hdma {
+    source: u16,
+    destination: u16,
+    // blocks of 16 bytes still to copy
+    blocks: u8,
+}
+
+// The cpu reaches the cart and work ram over one bus, and vram over another
+fn bus(address: u16) -> Option<u8> {
+    match address {
+        0x0000...0x7FFF | 0xA000...0xFDFF => Some(0),
+        0x8000...0x9FFF => Some(1),
+        _ => None,
+    }
+}
 
 pub struct Memory {
     count: u16,
     boot: [u8; gameboy::BOOTROM_SIZE],
+    // the boot rom covers the start of the cart until it is turned off through FF50
+    boot_mapped: bool,
     cart: cart::Cart,
+    // the color game boy's banked memory and HDMA
+    color: bool,
     wram: [u8; WRAM_SIZE],
+    wram_bank: usize,
     vram: [u8; VRAM_SIZE],
-    xram: [u8; XRAM_SIZE],
+    vram_bank: usize,
+    hdma: Option<Hdma>,
+    // clocks the cpu still has to wait for a HDMA, and the wait for blocks copied since the last
+    // tick, which only starts once the instruction that triggered them is over
+    stall: u16,
+    new_stall: u16,
+    // sprite attributes
+    oam: [u8; OAM_SIZE],
+    dma: Option<Dma>,
+    ppu: Ppu,
+    // the ppu keeps the cpu out of vram and oam while it is using them
+    ppu_blocking: bool,
     input: [u8; 1],
     io: [u8; IO_SIZE],
     hram: [u8; HRAM_SIZE],
     interrupt: [u8; 1],
     zero: [u8; 1],
+    // every byte sent out of the serial port
+    serial: Vec<u8>,
+    // watchers of cpu traffic
+    hooks: Vec<Hook>,
+    next_hook: usize,
+    // the instruction being run, and clocks since power on, for hooks
+    pc: u16,
+    cycles: u64,
 }
 
 impl Memory {
     pub fn new(boot: [u8; gameboy::BOOTROM_SIZE], cart: cart::Cart) -> Memory {
+        let mut io = [0; IO_SIZE];
+        // HDMA5 reads 0xFF until a transfer is running
+        io[0xFF55 - 0xFF01] = 0xFF;
+
         Memory {
             count: 0xFFFF,
             boot: boot,
+            boot_mapped: true,
             cart: cart,
+            color: false,
             wram: [127; WRAM_SIZE],
+            wram_bank: 1,
             vram: [127; VRAM_SIZE],
-            xram: [0; XRAM_SIZE],
-            input: [0],
-            io: [0; IO_SIZE],
+            vram_bank: 0,
+            hdma: None,
+            stall: 0,
+            new_stall: 0,
+            oam: [0; OAM_SIZE],
+            dma: None,
+            ppu: Ppu::new(),
+            ppu_blocking: true,
+            input: [0xCF],
+            io: io,
             hram: [0; HRAM_SIZE],
             interrupt: [0],
             zero: [0],
+            serial: Vec::new(),
+            hooks: Vec::new(),
+            next_hook: 0,
+            pc: 0,
+            cycles: 0,
         }
     }
 }
 
-impl Index<u16> for Memory {
-    type Output = u8;
+// What the dmg boot rom leaves in the io registers, from page 18 of gb.pdf plus IF and FF50
+const POST_BOOT_IO: [(u16, u8); 33] = [
+    (0xFF05, 0x00), (0xFF06, 0x00), (0xFF07, 0x00), (0xFF0F, 0xE1),
+    (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF14, 0xBF),
+    (0xFF16, 0x3F), (0xFF17, 0x00), (0xFF19, 0xBF), (0xFF1A, 0x7F),
+    (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1E, 0xBF), (0xFF20, 0xFF),
+    (0xFF21, 0x00), (0xFF22, 0x00), (0xFF23, 0xBF), (0xFF24, 0x77),
+    (0xFF25, 0xF3), (0xFF26, 0xF1), (0xFF40, 0x91), (0xFF42, 0x00),
+    (0xFF43, 0x00), (0xFF45, 0x00), (0xFF47, 0xFC), (0xFF48, 0xFF),
+    (0xFF49, 0xFF), (0xFF4A, 0x00), (0xFF4B, 0x00), (0xFF50, 0x01),
+    (0xFFFF, 0x00),
+];
 
-    fn index(&self, index: u16) -> &Self::Output {
-        match index {
-            0xFF00 => &self.input[0],
-            0xFFFF => &self.interrupt[0],
-            _ => &self[index..index + 1][0],
-        }
+// Where the other boot roms leave things differently, from the pan docs power up sequence
+fn post_boot_differences(model: gameboy::Model) -> &'static [(u16, u8)] {
+    match model {
+        gameboy::Model::Dmg | gameboy::Model::Mgb => &[],
+        gameboy::Model::Sgb => &[(0xFF26, 0xF0)],
+        gameboy::Model::Cgb => &[(0xFF4F, 0x00), (0xFF70, 0x00)],
     }
 }
 
-impl Index<usize> for Memory {
-    type Output = u8;
+impl Memory {
+    /// Set up the io registers as if `model`'s boot rom had just finished
+    pub fn skip_boot(&mut self, model: gameboy::Model) {
+        for &(address, value) in POST_BOOT_IO.iter().chain(post_boot_differences(model)) {
+            self.write(address, value);
+        }
+    }
+
+    /// Only the color game boy has SVBK, VBK and HDMA
+    pub fn set_model(&mut self, model: gameboy::Model) {
+        self.color = model == gameboy::Model::Cgb;
+    }
 
-    fn index(&self, index: usize) -> &Self::Output {
-        &self[index..index + 1][0]
+    /// Where the cart's real time clock, if it has one, gets the time from
+    pub fn set_clock(&mut self, clock: mbc::Clock) {
+        self.cart.set_clock(clock);
     }
-}
 
-impl IndexMut<u16> for Memory {
-    fn index_mut(&mut self, index: u16) -> &mut u8 {
-        &mut self[index as usize]
+    /// Everything the cart did that a frontend might want to react to, since the last call
+    pub fn take_events(&mut self) -> Vec<gameboy::Event> {
+        self.cart.take_events()
     }
-}
 
-impl IndexMut<usize> for Memory {
-    fn index_mut(&mut self, index: usize) -> &mut u8 {
-        match index {
-            0x0000...0x00FF => &mut self.boot[index - 0x0000],
-            0x0100...0x7FFF => &mut self.cart[index - 0x0100],
-            0x8000...0x9FFF => &mut self.vram[index - 0x8000],
-            0xA000...0xBFFF => &mut self.xram[index - 0xA000],
-            0xC000...0xDFFF => &mut self.wram[index - 0xC000],
-            0xE000...0xFDFF => &mut self.wram[index - 0xE000],
-            0xFF00 => &mut self.input[index - 0xFF00],
-            0xFF01...0xFF7F => &mut self.io[index - 0xFF01],
-            0xFF80...0xFFFE => &mut self.hram[index - 0xFF80],
-            0xFFFF => &mut self.interrupt[index - 0xFFFF],
-            _ => panic!("Address {:0>2X} has no known mapping!", index),
-        }
+    /// Save battery backed cart ram if it changed
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.cart.flush()
     }
-}
 
-impl Index<Range<u16>> for Memory {
-    type Output = [u8];
-    fn index(&self, range: Range<u16>) -> &Self::Output {
-        let usize_range = (range.start as usize)..(range.end as usize);
-        &self[usize_range]
+    pub fn serial(&self) -> &[u8] {
+        &self.serial
     }
-}
 
-impl Index<Range<usize>> for Memory {
-    type Output = [u8];
-    fn index(&self, range: Range<usize>) -> &Self::Output {
-        let end = if range.end.wrapping_sub(range.start) == 1 {
-            range.start
+    /**
+     * While an OAM DMA runs, the cpu can't reach OAM at all, and on the bus the DMA is reading from
+     * it sees whatever byte the DMA is copying, while its own writes go nowhere.
+     * Everything else, including HRAM where the code waiting for the DMA lives, works as usual
+     **/
+    fn dma_conflict(&self, address: u16) -> Option<u8> {
+        self.dma.as_ref().filter(|dma| dma.running()).and_then(|dma| {
+            let current = dma.source + dma.copied().min(DMA_LENGTH - 1);
+            match address {
+                0xFE00...0xFEFF => Some(0xFF),
+                _ if bus(address).is_some() && bus(address) == bus(dma.source) => Some(self.read_mapped(current)),
+                _ => None,
+            }
+        })
+    }
+
+    /// Let the cpu reach vram and oam whatever the ppu is doing, for debugging
+    pub fn set_ppu_blocking(&mut self, blocking: bool) {
+        self.ppu_blocking = blocking;
+    }
+
+    // Keep LY, STAT and IF in step with the ppu
+    fn step_ppu(&mut self, cycles: u8) {
+        let mode = if self.read_io(0xFF40) & 0x80 == 0 {
+            self.ppu.reset();
+            0
         } else {
-            range.end
+            if self.ppu.step(cycles) {
+                self.io[0xFF0F - 0xFF01] |= Interrupt::VBLANK as u8;
+            }
+            self.ppu.mode()
         };
 
-        match (range.start, end) {
-            (0x0000...0x00FF, 0x0000...0x00FF) => {
-                &self.boot[(range.start - 0x0000)..(range.end - 0x0000)]
+        self.io[0xFF44 - 0xFF01] = self.ppu.line();
+        self.set_ppu_mode(mode);
+    }
+
+    /// The ppu reports its mode in the low bits of STAT, which the cpu can't write.
+    /// Each hblank it enters lets a HDMA copy another block
+    fn set_ppu_mode(&mut self, mode: u8) {
+        let previous = self.read_io(0xFF41) & 0x03;
+        let stat = self.read_io(0xFF41) & !0x03 | mode & 0x03;
+        self.io[0xFF41 - 0xFF01] = stat;
+
+        if previous != 0 && mode == 0 && self.hdma.is_some() {
+            self.step_hdma();
+        }
+    }
+
+    // VRAM is off limits while the ppu draws (mode 3), and OAM from when it starts searching it (mode 2).
+    // Reads see 0xFF, and writes are lost
+    fn ppu_blocked(&self, address: u16) -> bool {
+        if !self.ppu_blocking || self.read_io(0xFF40) & 0x80 == 0 {
+            return false;
+        }
+
+        let mode = self.read_io(0xFF41) & 0x03;
+        match address {
+            0x8000...0x9FFF => mode == 3,
+            0xFE00...0xFE9F => mode == 2 || mode == 3,
+            _ => false,
+        }
+    }
+
+    /**
+     * Call `callback` for every `access` the cpu makes within `range`, with the byte
+     * read or written, or the opcode about to be executed. Reads and writes are seen as the
+     * cpu sees them, DMA and the ppu's own accesses are not. With no hooks, nothing is called.
+     * Hooks are called from reads too, so a hook that keeps count needs its own lock or atomic
+     **/
+    #[allow(dead_code)]
+    pub fn add_hook<F>(&mut self, access: Access, range: RangeInclusive<u16>, callback: F) -> HookId
+        where F: Fn(&Traffic) + Send + Sync + 'static
+    {
+        let id = HookId(self.next_hook);
+        self.next_hook += 1;
+        self.hooks.push(Hook {
+            id: id,
+            access: access,
+            range: range,
+            callback: Box::new(callback),
+        });
+        id
+    }
+
+    #[allow(dead_code)]
+    pub fn remove_hook(&mut self, id: HookId) {
+        self.hooks.retain(|hook| hook.id != id);
+    }
+
+    fn watch(&self, access: Access, address: u16, value: u8) {
+        let traffic = Traffic {
+            access: access,
+            address: address,
+            value: value,
+            pc: self.pc,
+            cycles: self.cycles,
+        };
+        for hook in &self.hooks {
+            if hook.access == access && hook.range.contains(&address) {
+                (hook.callback)(&traffic);
+            }
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        let value = match self.dma_conflict(address) {
+            Some(value) => value,
+            None if self.ppu_blocked(address) => 0xFF,
+            None => self.read_mapped(address),
+        };
+        if !self.hooks.is_empty() {
+            self.watch(Access::Read, address, value);
+        }
+        value
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if !self.hooks.is_empty() {
+            self.watch(Access::Write, address, value);
+        }
+        if self.dma_conflict(address).is_none() && !self.ppu_blocked(address) {
+            self.write_mapped(address, value);
+        }
+    }
+
+    // Start copying 160 bytes from XX00 into OAM. Sources past DFXX read the echo of work ram
+    fn start_dma(&mut self, value: u8) {
+        let page = if value >= 0xE0 { value - 0x20 } else { value };
+        self.dma = Some(Dma {
+            source: (page as u16) << 8,
+            started: false,
+            clocks: 0,
+        });
+    }
+
+    fn step_dma(&mut self, cycles: u8) {
+        if let Some(mut dma) = self.dma.take() {
+            if !dma.started {
+                dma.started = true;
+                self.dma = Some(dma);
+                return;
+            }
+
+            let from = dma.copied();
+            dma.clocks += cycles as u16;
+            let to = dma.copied().min(DMA_LENGTH);
+
+            for offset in from..to {
+                self.oam[offset as usize] = self.read_mapped(dma.source + offset);
+            }
+            if to < DMA_LENGTH {
+                self.dma = Some(dma);
+            }
+        }
+    }
+
+    /**
+     * Writing FF55 with bit 7 clear copies (value + 1) * 16 bytes from FF51/FF52 into vram at
+     * FF53/FF54 at once. With bit 7 set, one block is copied each hblank instead, until
+     * they are all done or FF55 is written with bit 7 clear. Either way the cpu waits
+     * while each block is copied
+     **/
+    fn start_hdma(&mut self, value: u8) {
+        if self.hdma.is_some() && value & 0x80 == 0 {
+            let blocks = self.hdma.take().map_or(0, |hdma| hdma.blocks);
+            self.io[0xFF55 - 0xFF01] = 0x80 | (blocks - 1);
+            return;
+        }
+
+        let register = |address: u16| self.io[address as usize - 0xFF01] as u16;
+        self.hdma = Some(Hdma {
+            source: (register(0xFF51) << 8 | register(0xFF52)) & 0xFFF0,
+            destination: 0x8000 | (register(0xFF53) << 8 | register(0xFF54)) & 0x1FF0,
+            blocks: (value & 0x7F) + 1,
+        });
+        self.io[0xFF55 - 0xFF01] = value & 0x7F;
+
+        if value & 0x80 == 0 {
+            while self.hdma.is_some() {
+                self.step_hdma();
             }
-            (0x0100...0x7FFF, 0x0100...0x7FFF) => {
-                &self.cart[(range.start - 0x0100)..(range.end - 0x0100)]
+        }
+    }
+
+    // Copy one block. A transfer that runs off the end of vram stops there
+    fn step_hdma(&mut self) {
+        if let Some(mut hdma) = self.hdma.take() {
+            for offset in 0..HDMA_BLOCK {
+                let byte = self.read_mapped(hdma.source.wrapping_add(offset));
+                let index = self.vram_index(hdma.destination + offset);
+                self.vram[index] = byte;
+            }
+            hdma.source = hdma.source.wrapping_add(HDMA_BLOCK);
+            hdma.destination += HDMA_BLOCK;
+            hdma.blocks -= 1;
+            self.new_stall += HDMA_CLOCKS_PER_BLOCK;
+
+            if hdma.blocks == 0 || hdma.destination > 0x9FFF {
+                self.io[0xFF55 - 0xFF01] = 0xFF;
+            } else {
+                self.io[0xFF55 - 0xFF01] = hdma.blocks - 1;
+                self.hdma = Some(hdma);
             }
-            (0x8000...0x9FFF, 0x8000...0x9FFF) => {
-                &self.vram[(range.start - 0x8000)..(range.end - 0x8000)]
+        }
+    }
+
+    fn vram_index(&self, address: u16) -> usize {
+        self.vram_bank * VRAM_BANK_SIZE + (address as usize - 0x8000)
+    }
+
+    // C000-CFFF is always bank 0, D000-DFFF is the bank picked by SVBK, and E000-FDFF echoes both
+    fn wram_index(&self, address: u16) -> usize {
+        let offset = (address as usize - 0xC000) & 0x1FFF;
+        if offset < WRAM_BANK_SIZE {
+            offset
+        } else {
+            self.wram_bank * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE
+        }
+    }
+
+    fn read_mapped(&self, address: u16) -> u8 {
+        let index = address as usize;
+        match address {
+            0x0000...0x00FF if self.boot_mapped => self.boot[index],
+            0x0000...0x7FFF => self.cart.read(address),
+            0x8000...0x9FFF => self.vram[self.vram_index(address)],
+            0xA000...0xBFFF => self.cart.read_ram(address),
+            0xC000...0xFDFF => self.wram[self.wram_index(address)],
+            0xFE00...0xFE9F => self.oam[index - 0xFE00],
+            0xFEA0...0xFEFF => self.zero[0],
+            0xFF00...0xFF7F => self.read_io(address) | unused_bits(address, self.color),
+            0xFF80...0xFFFE => self.hram[index - 0xFF80],
+            _ => self.interrupt[0],
+        }
+    }
+
+    /// A byte as the cpu would see it with the ppu and any DMA out of the way, without
+    /// calling any hooks. For crash reports and debug views
+    pub fn peek(&self, address: u16) -> u8 {
+        self.read_mapped(address)
+    }
+
+    /// Bytes from anywhere in the address space, across regions, as the cpu would see them
+    /// with the ppu and any DMA out of the way. For debug views
+    pub fn read_range(&self, range: RangeInclusive<u16>) -> Vec<u8> {
+        range.map(|address| self.read_mapped(address)).collect()
+    }
+
+    fn write_mapped(&mut self, address: u16, value: u8) {
+        let index = address as usize;
+        match address {
+            0x0000...0x7FFF => self.cart.write(address, value),
+            0x8000...0x9FFF => {
+                let index = self.vram_index(address);
+                self.vram[index] = value;
             }
-            (0xA000...0xBFFF, 0xA000...0xBFFF) => {
-                &self.xram[(range.start - 0xA000)..(range.end - 0xA000)]
+            0xA000...0xBFFF => self.cart.write_ram(address, value),
+            0xC000...0xFDFF => {
+                let index = self.wram_index(address);
+                self.wram[index] = value;
             }
-            (0xC000...0xDFFF, 0xC000...0xDFFF) => {
-                &self.wram[(range.start - 0xC000)..(range.end - 0xC000)]
+            0xFE00...0xFE9F => self.oam[index - 0xFE00] = value,
+            0xFEA0...0xFEFF => {}
+            0xFF00...0xFF7F => self.write_io(address, value),
+            0xFF80...0xFFFE => self.hram[index - 0xFF80] = value,
+            _ => self.interrupt[0] = value,
+        }
+    }
+
+    fn read_io(&self, address: u16) -> u8 {
+        match address {
+            // no buttons are wired up yet, so none of them pull their line low
+            0xFF00 => self.input[0] & 0x30 | 0x0F,
+            _ => self.io[address as usize - 0xFF01],
+        }
+    }
+
+    fn write_io(&mut self, address: u16, value: u8) {
+        match address {
+            // only the two select lines can be written, the buttons drive the rest
+            0xFF00 => self.input[0] = value & 0x30,
+            // Starting a transfer on the internal clock (SC bit 7 and 0) sends SB.
+            // There is never anything on the other end, so it finishes at once and shifts in 0xFF
+            0xFF02 if value & 0x81 == 0x81 => {
+                let data = self.read_io(0xFF01);
+                self.serial.push(data);
+                self.write_io(0xFF01, 0xFF);
+                self.write_io(0xFF02, value & 0x7F);
+                self.write_io(0xFF0F, self.read_io(0xFF0F) | Interrupt::SERIAL as u8);
             }
-            (0xE000...0xFDFF, 0xE000...0xFDFF) => {
-                &self.wram[(range.start - 0xE000)..(range.end - 0xE000)]
+            // once the boot rom is done it unmaps itself for good
+            0xFF50 => {
+                if value & 1 != 0 {
+                    self.boot_mapped = false;
+                }
+                self.io[0xFF50 - 0xFF01] = value;
             }
-            (0xFE00...0xFEFF, 0xFE00...0xFEFF) => &self.zero[..],
-            (0xFF00, 0xFF00) => &self.input[..],
-            (0xFF01...0xFF7F, 0xFF01...0xFF7F) => {
-                &self.io[(range.start - 0xFF01)..(range.end - 0xFF01)]
+            // the mode and coincidence bits of STAT are read only
+            0xFF41 => self.io[0xFF41 - 0xFF01] = value & 0x78 | self.read_io(0xFF41) & 0x07,
+            0xFF46 => {
+                self.io[0xFF46 - 0xFF01] = value;
+                self.start_dma(value);
             }
-            (0xFF80...0xFFFF, 0xFF80...0xFFFE) => {
-                &self.hram[(range.start - 0xFF80)..(range.end - 0xFF80)]
+            // any write to DIV resets it
+            0xFF04 => self.io[0xFF04 - 0xFF01] = 0,
+            // LY belongs to the ppu
+            0xFF44 => {}
+            0xFF4F if self.color => {
+                self.vram_bank = (value & 0x01) as usize;
+                self.io[0xFF4F - 0xFF01] = value;
             }
-            (0xFFFF, 0x0000) => &self.interrupt[..],
-            _ => {
-                panic!("Address {:0>4X}..{:0>4X} has no known mapping!",
-                       range.start,
-                       end)
+            0xFF55 if self.color => self.start_hdma(value),
+            // bank 0 can't be switched in at D000, asking for it gets bank 1
+            0xFF70 if self.color => {
+                self.wram_bank = if value & 0x07 == 0 { 1 } else { (value & 0x07) as usize };
+                self.io[0xFF70 - 0xFF01] = value;
             }
+            _ => self.io[address as usize - 0xFF01] = value,
+        }
+    }
+}
+
+// Bits that always read as 1, because the register doesn't use them, only takes writes, or isn't there at all.
+// Writes to them are kept but never seen
+fn unused_bits(address: u16, color: bool) -> u8 {
+    match address {
+        0xFF4F if color => 0xFE,
+        0xFF55 if color => 0x00,
+        0xFF70 if color => 0xF8,
+        0xFF00 => 0xC0,
+        0xFF02 => 0x7E,
+        0xFF07 => 0xF8,
+        0xFF0F => 0xE0,
+        0xFF10 => 0x80,
+        // the sound lengths and frequencies are write only
+        0xFF11 | 0xFF16 => 0x3F,
+        0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => 0xBF,
+        0xFF1A => 0x7F,
+        0xFF1C => 0x9F,
+        0xFF26 => 0x70,
+        0xFF41 => 0x80,
+        0xFF13 | 0xFF18 | 0xFF1B | 0xFF1D | 0xFF20 => 0xFF,
+        0xFF03 | 0xFF08...0xFF0E | 0xFF15 | 0xFF1F | 0xFF27...0xFF2F | 0xFF4C...0xFF7F => 0xFF,
+        _ => 0x00,
+    }
+}
+
+impl Bus for Memory {
+    fn read(&self, address: u16) -> u8 {
+        Memory::read(self, address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        Memory::write(self, address, value)
+    }
+
+    fn rom_bank(&self) -> usize {
+        self.cart.rom_bank()
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+        self.stall = self.stall.saturating_sub(cycles as u16);
+        self.step_dma(cycles);
+        self.step_ppu(cycles);
+        self.cart.tick(cycles);
+        self.stall += mem::replace(&mut self.new_stall, 0);
+    }
+
+    fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    fn execute(&mut self, pc: u16) {
+        self.pc = pc;
+        if !self.hooks.is_empty() {
+            let opcode = self.read_mapped(pc);
+            self.watch(Access::Execute, pc, opcode);
         }
     }
+
+    fn stalled(&self) -> bool {
+        self.stall > 0
+    }
+
+    fn hooked(&self) -> bool {
+        !self.hooks.is_empty()
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        Memory::peek(self, address)
+    }
 }
 
 impl Iterator for Memory {
@@ -149,9 +664,316 @@ impl Iterator for Memory {
 
         // check to see if we've finished counting or not.
         if self.count > 0 {
-            Some(self[self.count])
+            Some(self.read(self.count))
         } else {
             None
         }
     }
 }
+
+#[test]
+fn boot_rom_covers_the_cart_until_ff50() {
+    let mut rom = vec![0; 0x8000];
+    rom[0x0000] = 0xC3;
+    rom[0x0134] = 0x54;
+    let mut memory = Memory::new([0x31; gameboy::BOOTROM_SIZE], cart::Cart::new(rom));
+
+    assert_eq!(memory.read(0x0000), 0x31);
+    assert_eq!(memory.read(0x0134), 0x54);
+
+    memory.write(0xFF50, 0x01);
+    assert_eq!(memory.read(0x0000), 0xC3);
+    assert_eq!(memory.read_range(0x0000..=0x0001), vec![0xC3, 0x00]);
+}
+
+#[test]
+fn unmapped_reads_are_open_bus() {
+    let mut memory = Memory::new([0; gameboy::BOOTROM_SIZE], cart::Cart::new(vec![0; 0x8000]));
+    memory.write(0xFF03, 0x12);
+    memory.write(0xFF07, 0x05);
+    assert_eq!(memory.read(0xFF03), 0xFF);
+    assert_eq!(memory.read(0xFF07), 0xFD);
+    assert_eq!(memory.read(0xFF7F), 0xFF);
+    memory.write(0xFF13, 0x12);
+    memory.write(0xFF14, 0x05);
+    assert_eq!(memory.read(0xFF13), 0xFF);
+    assert_eq!(memory.read(0xFF14), 0xBF);
+
+    memory.write(0x9FFF, 0x34);
+    assert_eq!(memory.read_range(0x9FFE..=0xA000), vec![0x7F, 0x34, 0xFF]);
+}
+
+#[test]
+fn dma_copies_to_oam_while_only_hram_is_reachable() {
+    let mut memory = Memory::new([0; gameboy::BOOTROM_SIZE], cart::Cart::new(vec![0; 0x8000]));
+    for offset in 0..DMA_LENGTH {
+        memory.write(0xC100 + offset, offset as u8);
+    }
+    memory.write(0xFF80, 0x12);
+    memory.write(0xFF46, 0xC1);
+
+    // nothing happens during the LDH that started it, or the machine cycle after
+    Bus::tick(&mut memory, 12);
+    Bus::tick(&mut memory, 4);
+    assert_eq!(memory.oam[0], 0x00);
+
+    Bus::tick(&mut memory, 8);
+    assert_eq!(memory.read(0xFE00), 0xFF);
+    assert_eq!(memory.read(0xC000), 0x02);
+    assert_eq!(memory.read(0xFF80), 0x12);
+
+    for _ in 2..DMA_LENGTH - 1 {
+        Bus::tick(&mut memory, 4);
+    }
+    assert_eq!(memory.read(0xFE00), 0xFF);
+    Bus::tick(&mut memory, 4);
+    assert_eq!(memory.read(0xFE00), 0x00);
+    assert_eq!(memory.read(0xFE9F), 0x9F);
+    assert_eq!(memory.read(0xC000), 0x7F);
+}
+
+#[test]
+fn ppu_blocks_vram_while_drawing() {
+    let mut memory = Memory::new([0; gameboy::BOOTROM_SIZE], cart::Cart::new(vec![0; 0x8000]));
+    memory.write(0xFF40, 0x80);
+    memory.write(0x8000, 0x12);
+
+    memory.set_ppu_mode(3);
+    assert_eq!(memory.read(0x8000), 0xFF);
+    memory.write(0x8000, 0x34);
+    assert_eq!(memory.read(0xFE00), 0xFF);
+
+    memory.set_ppu_mode(0);
+    assert_eq!(memory.read(0x8000), 0x12);
+
+    memory.set_ppu_blocking(false);
+    memory.set_ppu_mode(3);
+    assert_eq!(memory.read(0x8000), 0x12);
+}
+
+#[test]
+fn hooks_see_the_traffic_they_watch() {
+    use std::sync::{Arc, Mutex};
+
+    fn shareable<T: Send + Sync>(_: &T) {}
+
+    let mut memory = Memory::new([0; gameboy::BOOTROM_SIZE], cart::Cart::new(vec![0; 0x8000]));
+    shareable(&memory);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    let id = memory.add_hook(Access::Write, 0xC000..=0xC0FF, move |traffic| log.lock().unwrap().push(*traffic));
+    let log = seen.clone();
+    memory.add_hook(Access::Read, 0xFF00..=0xFF7F, move |traffic| log.lock().unwrap().push(*traffic));
+
+    Bus::tick(&mut memory, 8);
+    Bus::execute(&mut memory, 0x0150);
+    memory.write(0xC010, 0x42);
+    memory.write(0xC100, 0x43);
+    memory.read(0xC010);
+    memory.remove_hook(id);
+    memory.write(0xC011, 0x44);
+
+    // the ppu keeping time, and peeks for debugging, go unnoticed
+    memory.write(0xFF40, 0x80);
+    Bus::tick(&mut memory, 255);
+    Bus::tick(&mut memory, 255);
+    memory.peek(0xFF44);
+
+    assert_eq!(*seen.lock().unwrap(), vec![Traffic {
+        access: Access::Write,
+        address: 0xC010,
+        value: 0x42,
+        pc: 0x0150,
+        cycles: 8,
+    }]);
+}
+
+#[test]
+fn hooks_only_see_the_cpu_reads_of_its_instructions() {
+    use std::sync::{Arc, Mutex, RwLock};
+    use cpu::Cpu;
+
+    // NOP / LDH A,(44) / NOP
+    let mut rom = vec![0; 0x8000];
+    rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xF0, 0x44, 0x00]);
+    let memory = Arc::new(RwLock::new(Memory::new([0; gameboy::BOOTROM_SIZE], cart::Cart::new(rom))));
+    memory.write().unwrap().skip_boot(gameboy::Model::Dmg);
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    memory.write().unwrap().add_hook(Access::Read, 0xFF00..=0xFFFF, move |traffic| {
+        log.lock().unwrap().push((traffic.address, traffic.pc))
+    });
+
+    let mut cpu = Cpu::new(memory.clone());
+    cpu.skip_boot(gameboy::Model::Dmg);
+    for _ in 0..3 {
+        cpu.step();
+    }
+    assert_eq!(*seen.lock().unwrap(), vec![(0xFF44, 0x0101)]);
+}
+
+#[test]
+fn color_banks_and_hdma() {
+    let mut memory = Memory::new([0; gameboy::BOOTROM_SIZE], cart::Cart::new(vec![0; 0x8000]));
+    memory.set_model(gameboy::Model::Cgb);
+    memory.set_ppu_blocking(false);
+    assert_eq!(memory.read(0xFF55), 0xFF);
+
+    memory.write(0xD000, 0x11);
+    memory.write(0xFF70, 0x02);
+    assert_eq!(memory.read(0xD000), 0x7F);
+    memory.write(0xD000, 0x22);
+    memory.write(0xFF70, 0x00);
+    assert_eq!(memory.read(0xD000), 0x11);
+    assert_eq!(memory.read(0xF000), 0x11);
+    assert_eq!(memory.read(0xFF70), 0xF8);
+
+    for offset in 0..0x20 {
+        memory.write(0xC000 + offset, offset as u8);
+    }
+    memory.write(0xFF4F, 0x01);
+    memory.write(0xFF51, 0xC0);
+    memory.write(0xFF52, 0x00);
+    memory.write(0xFF53, 0x01);
+    memory.write(0xFF54, 0x00);
+
+    // general purpose, both blocks at once
+    memory.write(0xFF55, 0x01);
+    assert_eq!(memory.read(0xFF55), 0xFF);
+    assert_eq!(memory.read(0x811F), 0x1F);
+    Bus::tick(&mut memory, 12);
+    assert!(Bus::stalled(&memory));
+    Bus::tick(&mut memory, 64);
+    assert!(!Bus::stalled(&memory));
+    memory.write(0xFF4F, 0x00);
+    assert_eq!(memory.read(0x811F), 0x7F);
+
+    // hblank, a block each time the ppu enters mode 0
+    memory.write(0xFF54, 0x40);
+    memory.write(0xFF55, 0x81);
+    assert_eq!(memory.read(0x8140), 0x7F);
+    memory.set_ppu_mode(3);
+    memory.set_ppu_mode(0);
+    assert_eq!(memory.read(0x8140), 0x00);
+    assert_eq!(memory.read(0x8150), 0x7F);
+    assert_eq!(memory.read(0xFF55), 0x00);
+    memory.set_ppu_mode(0);
+    assert_eq!(memory.read(0x8150), 0x7F);
+    memory.set_ppu_mode(3);
+    memory.set_ppu_mode(0);
+    assert_eq!(memory.read(0x815F), 0x1F);
+    assert_eq!(memory.read(0xFF55), 0xFF);
+}
+
+#[test]
+fn p1_only_takes_the_select_lines() {
+    let mut memory = Memory::new([0; gameboy::BOOTROM_SIZE], cart::Cart::new(vec![0; 0x8000]));
+    memory.write(0xFF00, 0x30);
+    assert_eq!(memory.read(0xFF00), 0xFF);
+    memory.write(0xFF00, 0x20);
+    assert_eq!(memory.read(0xFF00), 0xEF);
+}
+
+#[test]
+fn skip_boot_leaves_each_models_io() {
+    let mut memory = Memory::new([0; gameboy::BOOTROM_SIZE], cart::Cart::new(vec![0; 0x8000]));
+    memory.skip_boot(gameboy::Model::Dmg);
+    assert_eq!(memory.read(0xFF26), 0xF1);
+    assert_eq!(memory.read(0xFF40), 0x91);
+    assert!(!memory.boot_mapped);
+
+    let mut memory = Memory::new([0; gameboy::BOOTROM_SIZE], cart::Cart::new(vec![0; 0x8000]));
+    memory.skip_boot(gameboy::Model::Sgb);
+    assert_eq!(memory.read(0xFF26), 0xF0);
+
+    let mut memory = Memory::new([0; gameboy::BOOTROM_SIZE], cart::Cart::new(vec![0; 0x8000]));
+    memory.set_model(gameboy::Model::Cgb);
+    memory.skip_boot(gameboy::Model::Cgb);
+    assert_eq!(memory.read(0xFF4F), 0xFE);
+    assert_eq!(memory.read(0xFF70), 0xF8);
+}
+
+#[test]
+fn ppu_keeps_time_without_a_window() {
+    let mut memory = Memory::new([0; gameboy::BOOTROM_SIZE], cart::Cart::new(vec![0; 0x8000]));
+    memory.write(0xFF40, 0x80);
+    Bus::tick(&mut memory, 4);
+    assert_eq!(memory.read(0xFF41) & 0x03, 2);
+
+    for _ in 0..(456 * 144 / 4) {
+        Bus::tick(&mut memory, 4);
+    }
+    assert_eq!(memory.read(0xFF44), 144);
+    assert_eq!(memory.read(0xFF41) & 0x03, 1);
+    assert_eq!(memory.read(0xFF0F) & 0x01, 0x01);
+
+    memory.write(0xFF40, 0x00);
+    Bus::tick(&mut memory, 4);
+    assert_eq!(memory.read(0xFF44), 0);
+    assert_eq!(memory.read(0xFF41) & 0x03, 0);
+}
+
+#[test]
+fn ly_belongs_to_the_ppu() {
+    let mut memory = Memory::new([0; gameboy::BOOTROM_SIZE], cart::Cart::new(vec![0; 0x8000]));
+    memory.write(0xFF40, 0x80);
+    for _ in 0..(456 / 4) {
+        Bus::tick(&mut memory, 4);
+    }
+    memory.write(0xFF44, 0x42);
+    assert_eq!(memory.read(0xFF44), 1);
+}
+
+#[test]
+fn writes_dispatch_to_each_device() {
+    let mut rom = vec![0; 0x10000];
+    rom[0x147] = 0x01;
+    rom[0x8000] = 0x22;
+    let mut memory = Memory::new([0; gameboy::BOOTROM_SIZE], cart::Cart::new(rom));
+
+    // the rom is read only, the write picks a bank instead
+    memory.write(0x2000, 0x02);
+    assert_eq!(memory.read(0x4000), 0x22);
+    assert_eq!(memory.read(0x2000), 0x00);
+
+    memory.io[0xFF04 - 0xFF01] = 0x37;
+    memory.write(0xFF04, 0x99);
+    assert_eq!(memory.read(0xFF04), 0x00);
+
+    memory.write(0xFF01, b'P');
+    memory.write(0xFF02, 0x81);
+    assert_eq!(memory.serial(), b"P");
+    assert_eq!(memory.read(0xFF01), 0xFF);
+    assert_eq!(memory.read(0xFF02) & 0x80, 0x00);
+    assert_eq!(memory.read(0xFF0F) & Interrupt::SERIAL as u8, Interrupt::SERIAL as u8);
+}
+
+#[test]
+fn general_purpose_hdma_stalls_the_cpu_after_the_write() {
+    use std::sync::{Arc, RwLock};
+    use cpu::Cpu;
+
+    // LD A,00 / LDH (55),A / NOP
+    let mut rom = vec![0; 0x8000];
+    rom[0x0100..0x0105].copy_from_slice(&[0x3E, 0x00, 0xE0, 0x55, 0x00]);
+    let memory = Arc::new(RwLock::new(Memory::new([0; gameboy::BOOTROM_SIZE], cart::Cart::new(rom))));
+    {
+        let mut memory = memory.write().unwrap();
+        memory.set_model(gameboy::Model::Cgb);
+        memory.write(0xFF50, 0x01);
+        memory.write(0xFF51, 0xC0);
+        memory.write(0xFF53, 0x00);
+    }
+
+    let mut cpu = Cpu::new(memory.clone());
+    cpu.skip_boot(gameboy::Model::Cgb);
+    assert_eq!(cpu.step(), 8);
+    assert_eq!(cpu.step(), 12);
+
+    let mut clocks = 0;
+    while cpu.registers().pc == 0x0104 {
+        clocks += cpu.step() as u32;
+    }
+    assert_eq!(clocks, HDMA_CLOCKS_PER_BLOCK as u32 + 4);
+}
@@ -0,0 +1,58 @@
+// The ppu's line and mode timing, which the cpu sees through LY, STAT and the vblank interrupt.
+// It needs no window, so the headless runners keep time the same as the emulator
+
+// One scanline takes 456 clocks, and there are 144 visible lines plus 10 of vblank
+const LINE_CYCLES: usize = 456;
+const LINES: u8 = 154;
+const VBLANK_LINE: u8 = 144;
+
+// Each visible line searches OAM, then draws, then waits out the rest of the line in hblank
+const OAM_SEARCH_CYCLES: usize = 80;
+const DRAWING_CYCLES: usize = 172;
+
+pub struct Ppu {
+    // clocks into the current line
+    clock: usize,
+    line: u8,
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu { clock: 0, line: 0 }
+    }
+
+    /// With the lcd off, the ppu sits at the start of line 0 in hblank
+    pub fn reset(&mut self) {
+        self.clock = 0;
+        self.line = 0;
+    }
+
+    /// Let `cycles` clocks go by with the lcd on. True if vblank started
+    pub fn step(&mut self, cycles: u8) -> bool {
+        let mut vblank = false;
+        self.clock += cycles as usize;
+        while self.clock >= LINE_CYCLES {
+            self.clock -= LINE_CYCLES;
+            self.line = (self.line + 1) % LINES;
+            vblank |= self.line == VBLANK_LINE;
+        }
+        vblank
+    }
+
+    pub fn line(&self) -> u8 {
+        self.line
+    }
+
+    /// 2 while searching OAM, 3 while drawing, 0 in hblank and 1 in vblank
+    pub fn mode(&self) -> u8 {
+        if self.line >= VBLANK_LINE {
+            1
+        } else if self.clock < OAM_SEARCH_CYCLES {
+            2
+        } else if self.clock < OAM_SEARCH_CYCLES + DRAWING_CYCLES {
+            3
+        } else {
+            0
+        }
+    }
+}
@@ -0,0 +1,435 @@
+// Runs the SM83 single step tests, one json file of cases per opcode:
+//
+//   { "name": "86 0000",
+//     "initial": { "pc": 1, "sp": 2, "a": 3, ..., "ime": 0, "ie": 0, "ram": [[address, value], ...] },
+//     "final": { ...same as initial... },
+//     "cycles": [[address, value, "r-m"], null, ...] }
+//
+// Each case runs one instruction against a flat 64KiB memory, so nothing is mapped or banked.
+// Point SM83_TESTS at a checkout of the tests, it defaults to tests/sm83
+
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use serde_json;
+use serde_json::Value;
+
+use cpu::{Cpu, Registers};
+use memory::Bus;
+
+pub struct FlatMemory {
+    bytes: Vec<u8>,
+}
+
+impl FlatMemory {
+    pub fn new() -> FlatMemory {
+        FlatMemory { bytes: vec![0; 0x10000] }
+    }
+}
+
+impl Bus for FlatMemory {
+    fn read(&self, address: u16) -> u8 {
+        self.bytes[address as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.bytes[address as usize] = value;
+    }
+}
+
+fn number(state: &Value, key: &str) -> u16 {
+    state[key].as_u64().expect(&format!("{} should be a number", key)) as u16
+}
+
+fn registers(state: &Value) -> Registers {
+    Registers {
+        pc: number(state, "pc"),
+        sp: number(state, "sp"),
+        a: number(state, "a") as u8,
+        f: number(state, "f") as u8,
+        b: number(state, "b") as u8,
+        c: number(state, "c") as u8,
+        d: number(state, "d") as u8,
+        e: number(state, "e") as u8,
+        h: number(state, "h") as u8,
+        l: number(state, "l") as u8,
+        ime: state["ime"].as_u64() == Some(1),
+    }
+}
+
+fn ram(state: &Value) -> Vec<(u16, u8)> {
+    let mut bytes: Vec<(u16, u8)> = state["ram"].as_array().map_or(Vec::new(), |ram| {
+        ram.iter().map(|pair| (pair[0].as_u64().unwrap() as u16, pair[1].as_u64().unwrap() as u8)).collect()
+    });
+    if let Some(ie) = state["ie"].as_u64() {
+        bytes.push((0xFFFF, ie as u8));
+    }
+    bytes
+}
+
+/// Run one case, describing everything that differs from the expected final state
+pub fn run_case(case: &Value) -> Vec<String> {
+    let memory = Arc::new(RwLock::new(FlatMemory::new()));
+    for (address, value) in ram(&case["initial"]) {
+        memory.write().unwrap().write(address, value);
+    }
+
+    let mut cpu = Cpu::new(memory.clone());
+    cpu.set_registers(registers(&case["initial"]));
+    let cycles = cpu.step();
+
+    let mut mismatches = Vec::new();
+    let expected = registers(&case["final"]);
+    let actual = cpu.registers();
+
+    let registers = [("pc", expected.pc, actual.pc),
+                     ("sp", expected.sp, actual.sp),
+                     ("a", expected.a as u16, actual.a as u16),
+                     ("b", expected.b as u16, actual.b as u16),
+                     ("c", expected.c as u16, actual.c as u16),
+                     ("d", expected.d as u16, actual.d as u16),
+                     ("e", expected.e as u16, actual.e as u16),
+                     ("h", expected.h as u16, actual.h as u16),
+                     ("l", expected.l as u16, actual.l as u16),
+                     ("ime", expected.ime as u16, actual.ime as u16)];
+    for &(name, expected, actual) in registers.iter() {
+        if expected != actual {
+            mismatches.push(format!("{}: expected {:0>2X}, got {:0>2X}", name, expected, actual));
+        }
+    }
+
+    let flags = [("z", 7), ("n", 6), ("h", 5), ("c", 4)];
+    for &(name, bit) in flags.iter() {
+        let (expected, actual) = ((expected.f >> bit) & 1, (actual.f >> bit) & 1);
+        if expected != actual {
+            mismatches.push(format!("flag {}: expected {}, got {}", name, expected, actual));
+        }
+    }
+
+    for (address, value) in ram(&case["final"]) {
+        let actual = memory.read().unwrap().read(address);
+        if actual != value {
+            mismatches.push(format!("[{:0>4X}]: expected {:0>2X}, got {:0>2X}", address, value, actual));
+        }
+    }
+
+    if let Some(bus_cycles) = case["cycles"].as_array() {
+        let expected = bus_cycles.len() * 4;
+        if expected != cycles as usize {
+            mismatches.push(format!("cycles: expected {}, got {}", expected, cycles));
+        }
+    }
+
+    mismatches
+}
+
+/// Run every case in a json file, returning the failures prefixed by their case name
+pub fn run_file(path: &Path) -> Vec<String> {
+    let mut json = String::new();
+    fs::File::open(path).unwrap().read_to_string(&mut json).unwrap();
+    let cases: Value = serde_json::from_str(&json).expect(&format!("{:?} is not valid json", path));
+
+    let mut failures = Vec::new();
+    for case in cases.as_array().expect("a test file should be an array of cases") {
+        for mismatch in run_case(case) {
+            failures.push(format!("{}: {}", case["name"].as_str().unwrap_or("?"), mismatch));
+        }
+    }
+    failures
+}
+
+#[test]
+fn add_a_indirect_hl() {
+    let case = serde_json::from_str(r#"{
+        "name": "86 0000",
+        "initial": { "pc": 256, "sp": 65534, "a": 15, "b": 0, "c": 0, "d": 0, "e": 0, "f": 0,
+                     "h": 192, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 134], [49152, 1]] },
+        "final": { "pc": 257, "sp": 65534, "a": 16, "b": 0, "c": 0, "d": 0, "e": 0, "f": 32,
+                   "h": 192, "l": 0, "ime": 0, "ie": 0, "ram": [[256, 134], [49152, 1]] },
+        "cycles": [[256, 134, "r-m"], [49152, 1, "r-m"]]
+    }"#).unwrap();
+    assert_eq!(run_case(&case), Vec::<String>::new());
+}
+
+#[test]
+fn sm83() {
+    let dir = env::var("SM83_TESTS").unwrap_or("tests/sm83".to_string());
+    let dir = Path::new(&dir);
+    if !dir.is_dir() {
+        println!("{:?} not found, skipping the single step tests", dir);
+        return;
+    }
+
+    let mut failures = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().map_or(false, |extension| extension == "json") {
+            failures.extend(run_file(&path));
+        }
+    }
+
+    for failure in &failures {
+        println!("{}", failure);
+    }
+    assert!(failures.is_empty(), "{} mismatches", failures.len());
+}
+
+#[test]
+fn illegal_opcodes_lock_the_cpu() {
+    let memory = Arc::new(RwLock::new(FlatMemory::new()));
+    memory.write().unwrap().write(0x0000, 0xD3);
+
+    let mut cpu = Cpu::new(memory);
+    cpu.step();
+    for _ in 0..4 {
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.registers().pc, 0x0001);
+    }
+}
+
+#[test]
+fn illegal_opcodes_abort_with_a_crash_report() {
+    use cpu::IllegalPolicy;
+    use instruction::Instruction;
+
+    // NOP, then the illegal D3
+    let (memory, mut cpu) = interrupt_cpu(&[0x00, 0xD3], false, 0x1F, 0x00);
+    memory.write().unwrap().write(0xD000, 0x34);
+    memory.write().unwrap().write(0xFF40, 0x91);
+    cpu.set_illegal_policy(IllegalPolicy::Abort);
+
+    cpu.step();
+    assert!(cpu.running());
+    cpu.step();
+    assert!(!cpu.running());
+
+    let report = cpu.crash_report().unwrap();
+    assert_eq!((report.address, report.opcode), (0x0101, 0xD3));
+    assert_eq!(report.history.last(), Some(&(0x0101, Instruction::Illegal(0xD3))));
+    assert_eq!(report.registers.sp, 0xD000);
+    assert_eq!(report.stack[0], 0x34);
+    assert_eq!(report.io.len(), 0x80);
+    assert_eq!(report.io[0x40], 0x91);
+    assert_eq!(report.interrupt_enable, 0x1F);
+}
+
+// A cpu at 0x0100 with `program` there, the stack in work ram and IE and IF set
+fn interrupt_cpu(program: &[u8], ime: bool, enable: u8, flag: u8) -> (Arc<RwLock<FlatMemory>>, Cpu<FlatMemory>) {
+    let memory = Arc::new(RwLock::new(FlatMemory::new()));
+    {
+        let mut memory = memory.write().unwrap();
+        for (offset, &byte) in program.iter().enumerate() {
+            memory.write(0x0100 + offset as u16, byte);
+        }
+        memory.write(0xFFFF, enable);
+        memory.write(0xFF0F, flag);
+    }
+
+    let mut cpu = Cpu::new(memory.clone());
+    cpu.set_registers(Registers { pc: 0x0100, sp: 0xD000, ime: ime, ..Default::default() });
+    (memory, cpu)
+}
+
+#[test]
+fn interrupts_dispatch_by_priority_after_the_ei_delay() {
+    // EI, NOP, NOP, with a RETI at the vblank vector
+    let (memory, mut cpu) = interrupt_cpu(&[0xFB, 0x00, 0x00], false, 0x1F, 0x05);
+    memory.write().unwrap().write(0x0040, 0xD9);
+
+    assert_eq!(cpu.step(), 4);
+    assert_eq!(cpu.step(), 4);
+    assert_eq!(cpu.registers().pc, 0x0102);
+
+    // vblank goes first, and is acknowledged in IF
+    assert_eq!(cpu.step(), 20);
+    assert_eq!(cpu.registers().pc, 0x0040);
+    assert!(!cpu.registers().ime);
+    assert_eq!(memory.read().unwrap().read(0xFF0F), 0x04);
+    assert_eq!(memory.read().unwrap().read(0xCFFE), 0x02);
+    assert_eq!(memory.read().unwrap().read(0xCFFF), 0x01);
+
+    // RETI returns with interrupts enabled at once, so the timer is next
+    cpu.step();
+    assert_eq!(cpu.registers().pc, 0x0102);
+    assert!(cpu.registers().ime);
+    assert_eq!(cpu.step(), 20);
+    assert_eq!(cpu.registers().pc, 0x0050);
+    assert_eq!(memory.read().unwrap().read(0xFF0F), 0x00);
+}
+
+#[test]
+fn di_right_after_ei_cancels_it() {
+    let (_, mut cpu) = interrupt_cpu(&[0xFB, 0xF3, 0x00, 0x00], false, 0x01, 0x01);
+    for _ in 0..3 {
+        assert_eq!(cpu.step(), 4);
+    }
+    assert_eq!(cpu.registers().pc, 0x0103);
+    assert!(!cpu.registers().ime);
+}
+
+#[test]
+fn halt_waits_for_an_interrupt_with_or_without_ime() {
+    let (memory, mut cpu) = interrupt_cpu(&[0x76, 0x00, 0x00], true, 0x04, 0x00);
+    cpu.step();
+    assert_eq!(cpu.step(), 4);
+    assert_eq!(cpu.registers().pc, 0x0101);
+    memory.write().unwrap().write(0xFF0F, 0x04);
+    assert_eq!(cpu.step(), 24);
+    assert_eq!(cpu.registers().pc, 0x0050);
+
+    // without IME the cpu wakes up and carries on after the HALT
+    let (memory, mut cpu) = interrupt_cpu(&[0x76, 0x00, 0x00], false, 0x04, 0x00);
+    cpu.step();
+    assert_eq!(cpu.step(), 4);
+    memory.write().unwrap().write(0xFF0F, 0x04);
+    assert_eq!(cpu.step(), 8);
+    assert_eq!(cpu.registers().pc, 0x0102);
+}
+
+#[test]
+fn halt_bug_runs_the_next_byte_twice() {
+    // HALT, INC A with an interrupt already pending and IME off
+    let (_, mut cpu) = interrupt_cpu(&[0x76, 0x3C, 0x00], false, 0x04, 0x04);
+    cpu.step();
+    cpu.step();
+    assert_eq!(cpu.registers().pc, 0x0101);
+    cpu.step();
+    assert_eq!(cpu.registers().pc, 0x0102);
+    assert_eq!(cpu.registers().a, 2);
+}
+
+#[test]
+fn ei_halt_with_an_interrupt_pending_returns_to_the_halt() {
+    // EI, HALT, with INC A, RETI at the timer vector
+    let (memory, mut cpu) = interrupt_cpu(&[0xFB, 0x76, 0x00], false, 0x04, 0x04);
+    memory.write().unwrap().write(0x0050, 0x3C);
+    memory.write().unwrap().write(0x0051, 0xD9);
+
+    cpu.step();
+    cpu.step();
+    assert_eq!(cpu.step(), 20);
+    assert_eq!(cpu.registers().pc, 0x0050);
+    cpu.step();
+    cpu.step();
+    assert_eq!(cpu.registers().a, 1);
+    assert_eq!(cpu.registers().pc, 0x0101);
+    assert_eq!(cpu.registers().sp, 0xD000);
+}
+
+#[test]
+fn stop_waits_for_a_button() {
+    let (memory, mut cpu) = interrupt_cpu(&[0x10, 0x00, 0x00], false, 0x00, 0x00);
+    memory.write().unwrap().write(0xFF00, 0xFF);
+    cpu.step();
+    let stopped = cpu.registers().pc;
+    for _ in 0..4 {
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.registers().pc, stopped);
+    }
+
+    memory.write().unwrap().write(0xFF00, 0xEE);
+    cpu.step();
+    cpu.step();
+    assert_eq!(cpu.registers().pc, stopped + 1);
+}
+
+#[test]
+fn skip_boot_leaves_each_models_registers() {
+    use gameboy::Model;
+
+    let expected = [(Model::Dmg, 0x01, 0xB0, 0x4D), (Model::Mgb, 0xFF, 0xB0, 0x4D),
+                    (Model::Sgb, 0x01, 0x00, 0x60), (Model::Cgb, 0x11, 0x80, 0x0D)];
+    for &(model, a, f, l) in expected.iter() {
+        let mut cpu = Cpu::new(Arc::new(RwLock::new(FlatMemory::new())));
+        cpu.skip_boot(model);
+        let registers = cpu.registers();
+        assert_eq!((registers.a, registers.f, registers.l), (a, f, l), "{:?}", model);
+        assert_eq!((registers.pc, registers.sp), (0x0100, 0xFFFE));
+    }
+}
+
+// Run the instructions in `program` from 0x0100, starting from `registers`
+fn run_program(program: &[u8], instructions: usize, registers: Registers) -> (Arc<RwLock<FlatMemory>>, Cpu<FlatMemory>) {
+    let (memory, mut cpu) = interrupt_cpu(program, false, 0x00, 0x00);
+    cpu.set_registers(Registers { pc: 0x0100, ..registers });
+    for _ in 0..instructions {
+        cpu.step();
+    }
+    (memory, cpu)
+}
+
+#[test]
+fn adc_and_sbc_carry_into_the_half_carry() {
+    // ADC A,00 with carry in
+    let (_, cpu) = run_program(&[0xCE, 0x00], 1, Registers { a: 0x0F, f: 0x10, ..Default::default() });
+    assert_eq!((cpu.registers().a, cpu.registers().f), (0x10, 0x20));
+
+    // SBC A,00 with carry in
+    let (_, cpu) = run_program(&[0xDE, 0x00], 1, Registers { a: 0x10, f: 0x10, ..Default::default() });
+    assert_eq!((cpu.registers().a, cpu.registers().f), (0x0F, 0x60));
+}
+
+#[test]
+fn daa_after_a_subtraction() {
+    // SUB 01, DAA
+    let (_, cpu) = run_program(&[0xD6, 0x01, 0x27], 2, Registers { a: 0x10, ..Default::default() });
+    assert_eq!((cpu.registers().a, cpu.registers().f), (0x09, 0x40));
+}
+
+#[test]
+fn sp_plus_a_negative_offset() {
+    // ADD SP,-1 carries out of both the low nibble and the low byte
+    let (_, cpu) = run_program(&[0xE8, 0xFF], 1, Registers { sp: 0x0001, f: 0x80, ..Default::default() });
+    assert_eq!((cpu.registers().sp, cpu.registers().f), (0x0000, 0x30));
+
+    // LD HL,SP-2 carries out of neither
+    let (_, cpu) = run_program(&[0xF8, 0xFE], 1, Registers { sp: 0x0000, ..Default::default() });
+    let registers = cpu.registers();
+    assert_eq!((registers.h, registers.l, registers.sp, registers.f), (0xFF, 0xFE, 0x0000, 0x00));
+}
+
+#[test]
+fn pop_af_drops_the_low_nibble_of_f() {
+    let (memory, mut cpu) = interrupt_cpu(&[0xF1], false, 0x00, 0x00);
+    memory.write().unwrap().write(0xD000, 0xFF);
+    memory.write().unwrap().write(0xD001, 0x12);
+    cpu.step();
+    assert_eq!((cpu.registers().a, cpu.registers().f, cpu.registers().sp), (0x12, 0xF0, 0xD002));
+}
+
+#[test]
+fn rotates_through_carry() {
+    // RL C, then RR C
+    let (_, cpu) = run_program(&[0xCB, 0x11], 1, Registers { c: 0x80, ..Default::default() });
+    assert_eq!((cpu.registers().c, cpu.registers().f), (0x00, 0x90));
+
+    let (_, cpu) = run_program(&[0xCB, 0x19], 1, Registers { c: 0x01, f: 0x10, ..Default::default() });
+    assert_eq!((cpu.registers().c, cpu.registers().f), (0x80, 0x10));
+}
+
+#[test]
+fn swap_clears_carry() {
+    let (_, cpu) = run_program(&[0xCB, 0x37], 1, Registers { a: 0xF0, f: 0x10, ..Default::default() });
+    assert_eq!((cpu.registers().a, cpu.registers().f), (0x0F, 0x00));
+}
+
+#[test]
+fn bit_set_and_res_on_indirect_hl() {
+    // BIT 0,(HL), SET 7,(HL), RES 0,(HL)
+    let (memory, mut cpu) = interrupt_cpu(&[0xCB, 0x46, 0xCB, 0xFE, 0xCB, 0x86], false, 0x00, 0x00);
+    cpu.set_registers(Registers { pc: 0x0100, h: 0xC0, f: 0x10, ..Default::default() });
+    memory.write().unwrap().write(0xC000, 0x00);
+
+    cpu.step();
+    assert_eq!(cpu.registers().f, 0xB0);
+
+    memory.write().unwrap().write(0xC000, 0x01);
+    cpu.step();
+    assert_eq!(memory.read().unwrap().read(0xC000), 0x81);
+    cpu.step();
+    assert_eq!(memory.read().unwrap().read(0xC000), 0x80);
+    assert_eq!(cpu.registers().f, 0xB0);
+}
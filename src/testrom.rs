@@ -0,0 +1,164 @@
+// Headless runners for the community test roms, which report through the serial port
+// or a register signature instead of the screen
+
+use std::sync::{Arc, RwLock};
+
+use cart;
+use cpu;
+use gameboy;
+use mbc;
+use memory;
+
+// cpu_instrs, the slowest of blargg's roms, takes under a minute
+pub const BLARGG_BUDGET: u64 = gameboy::CLOCK * 60;
+
+// mooneye's roms finish within a couple of seconds
+pub const MOONEYE_BUDGET: u64 = gameboy::CLOCK * 10;
+
+// What mooneye's roms leave in B, C, D, E, H and L when they pass
+const FIBONACCI: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+// LD B,B marks the end of a mooneye rom
+const LD_B_B: u8 = 0x40;
+
+/// How a test rom finished
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+    Passed,
+    Failed,
+    TimedOut,
+}
+
+impl Verdict {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Verdict::Passed => 0,
+            Verdict::Failed => 1,
+            Verdict::TimedOut => 2,
+        }
+    }
+}
+
+// Start at the cartridge entry point, without a window or a boot rom
+fn boot(cart: cart::Cart) -> (Arc<RwLock<memory::Memory>>, cpu::Cpu) {
+    let memory = Arc::new(RwLock::new(memory::Memory::new([0; gameboy::BOOTROM_SIZE], cart)));
+    memory.write().unwrap().skip_boot(gameboy::Model::Dmg);
+
+    let mut cpu = cpu::Cpu::new(memory.clone());
+    cpu.set_illegal_policy(cpu::IllegalPolicy::Abort);
+    cpu.skip_boot(gameboy::Model::Dmg);
+    // so the real time clock runs the same every time
+    memory.write().unwrap().set_clock(mbc::Clock::Emulated);
+    (memory, cpu)
+}
+
+/// Run one of blargg's roms until it prints "Passed" or "Failed" over serial,
+/// or `budget` clocks go by. Returns the verdict and everything it printed
+pub fn blargg(cart: cart::Cart, budget: u64) -> (Verdict, String) {
+    let (memory, mut cpu) = boot(cart);
+    let mut clocks = 0;
+    let mut printed = 0;
+
+    while clocks < budget && cpu.running() {
+        clocks += cpu.step() as u64;
+
+        let memory = memory.read().unwrap();
+        if memory.serial().len() != printed {
+            printed = memory.serial().len();
+            let output = String::from_utf8_lossy(memory.serial()).into_owned();
+            if output.contains("Passed") {
+                return (Verdict::Passed, output);
+            }
+            if output.contains("Failed") {
+                return (Verdict::Failed, output);
+            }
+        }
+    }
+
+    let mut output = String::from_utf8_lossy(memory.read().unwrap().serial()).into_owned();
+    match cpu.crash_report() {
+        Some(report) => {
+            output.push_str(&report.to_string());
+            (Verdict::Failed, output)
+        }
+        None => (Verdict::TimedOut, output),
+    }
+}
+
+/// Run one of mooneye's roms until it executes LD B,B, or `budget` clocks go by.
+/// Returns the verdict and the registers it finished with
+pub fn mooneye(cart: cart::Cart, budget: u64) -> (Verdict, cpu::Registers) {
+    let (memory, mut cpu) = boot(cart);
+    let mut clocks = 0;
+
+    while clocks < budget && cpu.running() {
+        let registers = cpu.registers();
+        if memory.read().unwrap().peek(registers.pc) == LD_B_B {
+            let signature = [registers.b, registers.c, registers.d, registers.e, registers.h, registers.l];
+            let verdict = if signature == FIBONACCI { Verdict::Passed } else { Verdict::Failed };
+            return (verdict, registers);
+        }
+        clocks += cpu.step() as u64;
+    }
+
+    (if cpu.running() { Verdict::TimedOut } else { Verdict::Failed }, cpu.registers())
+}
+
+// Every .gb file under `dir`, which can be overridden by the `var` environment variable
+#[cfg(test)]
+fn test_roms(var: &str, dir: &str) -> Vec<::std::path::PathBuf> {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn walk(dir: PathBuf, roms: &mut Vec<PathBuf>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                walk(path, roms);
+            } else if path.extension().map_or(false, |extension| extension == "gb") {
+                roms.push(path);
+            }
+        }
+    }
+
+    let dir = PathBuf::from(env::var(var).unwrap_or(dir.to_string()));
+    let mut roms = Vec::new();
+    if dir.is_dir() {
+        walk(dir, &mut roms);
+    } else {
+        println!("{:?} not found, skipping its test roms", dir);
+    }
+    roms.sort();
+    roms
+}
+
+#[test]
+fn blargg_roms() {
+    use load_cart;
+
+    let mut failures = Vec::new();
+    for path in test_roms("BLARGG_ROMS", "roms/blargg") {
+        let (verdict, output) = blargg(load_cart(&path), BLARGG_BUDGET);
+        println!("{:?}: {:?}\n{}", path, verdict, output);
+        if verdict != Verdict::Passed {
+            failures.push(path);
+        }
+    }
+    assert!(failures.is_empty(), "failed: {:?}", failures);
+}
+
+#[test]
+fn mooneye_roms() {
+    use load_cart;
+
+    let mut failures = Vec::new();
+    for path in test_roms("MOONEYE_ROMS", "roms/mooneye") {
+        let (verdict, _) = mooneye(load_cart(&path), MOONEYE_BUDGET);
+        println!("{:?}: {:?}", path, verdict);
+        if verdict != Verdict::Passed {
+            failures.push(path);
+        }
+    }
+    assert!(failures.is_empty(), "failed: {:?}", failures);
+}